@@ -1,5 +1,10 @@
+use std::{env, path::PathBuf};
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::configure().compile(&["proto/factory.proto"], &["."])?;
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("factory_descriptor.bin"))
+        .compile(&["proto/factory.proto"], &["."])?;
     tonic_build::configure().compile(
         &["../fr-pmx-registry/proto/registry.proto"],
         &["../fr-pmx-registry/"],
@@ -1,7 +1,10 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use pmx::factory::{
-    channel_strip::PmxChannelStripType, pmx_factory_client::PmxFactoryClient,
-    CreateChannelStripRequest,
+    channel_strip::{PmxChannelLayout, PmxChannelStripType, PmxParameterPreset},
+    pmx_factory_client::PmxFactoryClient,
+    CollectGarbageRequest, CreateChannelStripRequest, DeleteChannelStripRequest,
+    DeleteOutputStageRequest, GetQueueStatusRequest, ListTemplatesRequest, ReconcileRequest,
+    RestoreFromRegistryRequest,
 };
 use tonic::Request;
 
@@ -33,7 +36,62 @@ enum Commands {
         name: String,
         #[arg(short, long)]
         basic: bool,
+        #[arg(short, long)]
+        template: Option<String>,
+        #[arg(short, long, value_enum, default_value_t = Layout::Stereo)]
+        layout: Layout,
+        /// Initial parameter value as slot.symbol=value, can be repeated
+        #[arg(short, long, value_parser = parse_parameter_preset)]
+        parameter: Vec<PmxParameterPreset>,
+        /// Recreate links that are missing after the channel strip was built
+        #[arg(short, long)]
+        repair_links: bool,
+    },
+    DeleteChannelStrip {
+        #[arg(short, long)]
+        id: u32,
+    },
+    DeleteOutputStage {
+        #[arg(short, long)]
+        id: u32,
+    },
+    ListTemplates,
+    QueueStatus,
+    Reconcile {
+        /// Recreate missing plugins and links instead of only reporting them
+        #[arg(short, long)]
+        repair: bool,
+    },
+    CollectGarbage {
+        /// Remove the orphaned plugins instead of only listing them
+        #[arg(short, long)]
+        confirm: bool,
     },
+    RestoreFromRegistry,
+}
+
+#[derive(Clone, ValueEnum)]
+enum Layout {
+    Mono,
+    Stereo,
+    Surround51,
+}
+
+fn parse_parameter_preset(argument: &str) -> Result<PmxParameterPreset, String> {
+    let (target, value) = argument
+        .split_once('=')
+        .ok_or_else(|| format!("expected slot.symbol=value, got '{argument}'"))?;
+    let (slot, symbol) = target
+        .split_once('.')
+        .ok_or_else(|| format!("expected slot.symbol=value, got '{argument}'"))?;
+    let value = value
+        .parse()
+        .map_err(|_| format!("'{value}' is not a number"))?;
+    Ok(PmxParameterPreset {
+        slot: String::from(slot),
+        symbol: String::from(symbol),
+        value,
+    })
 }
 
 #[tokio::main]
@@ -43,7 +101,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     if let Some(command) = cli_arguments.command {
         match command {
-            Commands::CreateChannelStrip { name, basic } => {
+            Commands::CreateChannelStrip {
+                name,
+                basic,
+                template,
+                layout,
+                parameter,
+                repair_links,
+            } => {
                 let mut client = PmxFactoryClient::connect(service_urls.pmx_factory_url).await?;
                 let request = Request::new(CreateChannelStripRequest {
                     name,
@@ -51,10 +116,60 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         true => PmxChannelStripType::Basic as i32,
                         false => PmxChannelStripType::CrossFaded as i32,
                     },
+                    template,
+                    parameters: parameter,
+                    channel_layout: match layout {
+                        Layout::Mono => PmxChannelLayout::Mono as i32,
+                        Layout::Stereo => PmxChannelLayout::Stereo as i32,
+                        Layout::Surround51 => PmxChannelLayout::Surround51 as i32,
+                    },
+                    repair_links,
                 });
                 let response = client.create_channel_strip(request).await?;
                 println!("{response:#?}");
             }
+            Commands::DeleteChannelStrip { id } => {
+                let mut client = PmxFactoryClient::connect(service_urls.pmx_factory_url).await?;
+                let request = Request::new(DeleteChannelStripRequest { id });
+                let response = client.delete_channel_strip(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::DeleteOutputStage { id } => {
+                let mut client = PmxFactoryClient::connect(service_urls.pmx_factory_url).await?;
+                let request = Request::new(DeleteOutputStageRequest { id });
+                let response = client.delete_output_stage(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::ListTemplates => {
+                let mut client = PmxFactoryClient::connect(service_urls.pmx_factory_url).await?;
+                let request = Request::new(ListTemplatesRequest {});
+                let response = client.list_templates(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::QueueStatus => {
+                let mut client = PmxFactoryClient::connect(service_urls.pmx_factory_url).await?;
+                let request = Request::new(GetQueueStatusRequest {});
+                let response = client.get_queue_status(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::Reconcile { repair } => {
+                let mut client = PmxFactoryClient::connect(service_urls.pmx_factory_url).await?;
+                let request = Request::new(ReconcileRequest { repair });
+                let response = client.reconcile(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::CollectGarbage { confirm } => {
+                let mut client = PmxFactoryClient::connect(service_urls.pmx_factory_url).await?;
+                let request = Request::new(CollectGarbageRequest { confirm });
+                let response = client.collect_garbage(request).await?;
+                println!("{response:#?}");
+            }
+            Commands::RestoreFromRegistry => {
+                let mut client = PmxFactoryClient::connect(service_urls.pmx_factory_url).await?;
+                let request = Request::new(RestoreFromRegistryRequest {});
+                let response = client.restore_from_registry(request).await?;
+                println!("{response:#?}");
+            }
         }
     }
 
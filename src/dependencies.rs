@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use fr_logging::Logger;
+use tonic::transport::Channel;
+use tonic::Status;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+use crate::factory::pmx::{
+    mod_host::{mod_host_proxy_client::ModHostProxyClient, ListPluginInstancesRequest},
+    pipewire::{pipewire_client::PipewireClient, ListNodesRequest},
+    pmx_registry_client::PmxRegistryClient,
+    ListChannelStripsRequest,
+};
+use crate::factory_service::{pmx::factory::pmx_factory_server::PmxFactoryServer, FactoryService};
+
+/// A service the factory cannot do any work without.
+pub enum Dependency {
+    ModHost(ModHostProxyClient<Channel>),
+    Registry(PmxRegistryClient<Channel>),
+    Pipewire(PipewireClient<Channel>),
+}
+
+impl Dependency {
+    fn name(&self) -> &'static str {
+        match self {
+            Dependency::ModHost(_) => "mod host proxy",
+            Dependency::Registry(_) => "pmx registry",
+            Dependency::Pipewire(_) => "pipewire registry",
+        }
+    }
+
+    /// Makes a cheap read-only call to check that the service answers.
+    async fn probe(&self, timeout: Duration) -> Result<(), Status> {
+        let call = async {
+            match self {
+                Dependency::ModHost(client) => client
+                    .clone()
+                    .list_plugin_instances(ListPluginInstancesRequest {})
+                    .await
+                    .map(|_| ()),
+                Dependency::Registry(client) => client
+                    .clone()
+                    .list_channel_strips(ListChannelStripsRequest {})
+                    .await
+                    .map(|_| ()),
+                Dependency::Pipewire(client) => client
+                    .clone()
+                    .list_nodes(ListNodesRequest {})
+                    .await
+                    .map(|_| ()),
+            }
+        };
+        tokio::time::timeout(timeout, call)
+            .await
+            .unwrap_or_else(|_| Err(Status::deadline_exceeded("probe timed out")))
+    }
+}
+
+/// Keeps the health status of the factory in line with its dependencies. The
+/// factory is serving while all of them answer and not serving otherwise.
+/// Every dependency is probed once per `probe_interval`, and a probe that
+/// takes longer than `probe_timeout` counts as failed.
+pub async fn report_health(
+    dependencies: Vec<Dependency>,
+    probe_interval: Duration,
+    probe_timeout: Duration,
+    mut health_reporter: HealthReporter,
+    logger: Logger,
+) {
+    let mut reachable = vec![false; dependencies.len()];
+    let mut status = ServingStatus::NotServing;
+    let mut interval = tokio::time::interval(probe_interval);
+    loop {
+        interval.tick().await;
+        for (dependency, reachable) in dependencies.iter().zip(reachable.iter_mut()) {
+            match (dependency.probe(probe_timeout).await, *reachable) {
+                (Ok(()), false) => {
+                    logger.log_info(&format!("{} is reachable", dependency.name()));
+                    *reachable = true;
+                }
+                (Err(error), true) => {
+                    logger.log_error(&format!(
+                        "{} is no longer reachable: {}",
+                        dependency.name(),
+                        error.message()
+                    ));
+                    *reachable = false;
+                }
+                _ => {}
+            }
+        }
+
+        let new_status = match reachable.iter().all(|reachable| *reachable) {
+            true => ServingStatus::Serving,
+            false => ServingStatus::NotServing,
+        };
+        if new_status != status {
+            logger.log_info(&format!("Factory health changed to {new_status:?}"));
+            health_reporter.set_service_status("", new_status).await;
+            match new_status {
+                ServingStatus::Serving => {
+                    health_reporter
+                        .set_serving::<PmxFactoryServer<FactoryService>>()
+                        .await
+                }
+                _ => {
+                    health_reporter
+                        .set_not_serving::<PmxFactoryServer<FactoryService>>()
+                        .await
+                }
+            }
+            status = new_status;
+        }
+    }
+}
@@ -1,13 +1,49 @@
-use channel_strip_factory::{create_channel_strip, ChannelStripPlugins};
+use channel_strip_factory::{
+    channel_strip_output_plugin_id, create_channel_strip, delete_channel_strip, registry_slots,
+    ChannelStripDefinition, ChannelStripPlugins,
+};
+use downstream::CallPolicy;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
 use fr_logging::Logger;
+use fr_pmx_config_lib::ChannelStripTemplateConfig;
+use locks::{ObjectKey, ObjectLocks};
 use pmx::{
-    channel_strip::PmxChannelStripType, mod_host::plugins::PmxPlugin,
-    pmx_registry_client::PmxRegistryClient, RegisterOutputStageRequest,
+    channel_strip::{PmxChannelLayout, PmxChannelStrip, PmxChannelStripType},
+    mod_host::plugins::PmxPlugin,
+    output_stage::PmxOutputStage,
+    pmx_registry_client::PmxRegistryClient,
+    GetChannelStripRequest, GetOutputStageRequest, ListChannelStripsRequest,
+    ListOutputStagesRequest, RegisterOutputStageRequest, UnregisterChannelStripRequest,
+    UnregisterOutputStageRequest,
 };
-use tonic::{transport::Channel, Request};
+use tokio::sync::{oneshot, RwLock, Semaphore};
+use tokio_util::sync::CancellationToken;
+use tonic::transport::Channel;
+use transaction::Transaction;
+
+pub use channel_strip_factory::ChannelStripSlot;
+pub use error::FactoryError;
+pub use garbage_collection::OrphanedPlugin;
+pub use journal::Journal;
+pub use reconcile::Drift;
+pub use templates::ParameterPreset;
+pub use verification::LinkDiscrepancy;
 
 mod channel_strip_factory;
+mod downstream;
+mod error;
+mod garbage_collection;
+mod journal;
+mod locks;
+mod ports;
+mod reconcile;
+mod templates;
+mod transaction;
 mod utils;
+mod verification;
 
 pub mod pmx {
     tonic::include_proto!("pmx");
@@ -74,11 +110,11 @@ pub struct CreateChannelStripResponse {
     pub id: u32,
     pub name: String,
     pub cross_fader: Option<PmxPlugin>,
-    pub saturator: PmxPlugin,
-    pub compressor: PmxPlugin,
-    pub equalizer: PmxPlugin,
-    pub gain: PmxPlugin,
+    pub slots: Vec<ChannelStripSlot>,
     pub channel_type: PmxChannelStripType,
+    pub channel_layout: PmxChannelLayout,
+    pub template: Option<String>,
+    pub missing_links: Vec<LinkDiscrepancy>,
 }
 
 #[derive(Debug)]
@@ -88,196 +124,797 @@ pub struct CreateOutputStageResponse {
     pub left_channel_strip_id: u32,
     pub right_channel_strip_id: u32,
     pub cross_fader_plugin_id: u32,
+    pub missing_links: Vec<LinkDiscrepancy>,
 }
 
 pub enum FactoryRequest {
     CreateChannelStrip {
         name: String,
-        response_sender: tokio::sync::oneshot::Sender<CreateChannelStripResponse>,
+        response_sender:
+            tokio::sync::oneshot::Sender<Result<CreateChannelStripResponse, FactoryError>>,
         channel_type: PmxChannelStripType,
+        channel_layout: PmxChannelLayout,
+        template: Option<String>,
+        parameters: Vec<ParameterPreset>,
+        repair_links: bool,
     },
     CreateOutputStage {
         name: String,
-        response_sender: tokio::sync::oneshot::Sender<CreateOutputStageResponse>,
+        repair_links: bool,
+        response_sender:
+            tokio::sync::oneshot::Sender<Result<CreateOutputStageResponse, FactoryError>>,
+    },
+    DeleteChannelStrip {
+        id: u32,
+        response_sender: tokio::sync::oneshot::Sender<Result<(), FactoryError>>,
+    },
+    DeleteOutputStage {
+        id: u32,
+        response_sender: tokio::sync::oneshot::Sender<Result<(), FactoryError>>,
+    },
+    ListTemplates {
+        response_sender:
+            tokio::sync::oneshot::Sender<Result<Vec<ChannelStripTemplateConfig>, FactoryError>>,
+    },
+    Reconcile {
+        repair: bool,
+        response_sender: tokio::sync::oneshot::Sender<Result<Vec<Drift>, FactoryError>>,
+    },
+    CollectGarbage {
+        confirm: bool,
+        response_sender: tokio::sync::oneshot::Sender<Result<Vec<OrphanedPlugin>, FactoryError>>,
+    },
+    RestoreFromRegistry {
+        response_sender: tokio::sync::oneshot::Sender<Result<Vec<Drift>, FactoryError>>,
     },
 }
 
+const ROLLBACK_RETRY_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const ROLLBACK_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
 pub struct Factory {
-    receiver: tokio::sync::mpsc::UnboundedReceiver<FactoryRequest>,
+    receiver: tokio::sync::mpsc::Receiver<FactoryRequest>,
+    worker: Arc<FactoryWorker>,
+    request_permits: Arc<Semaphore>,
+    max_concurrent_requests: u32,
+}
+
+/// The state shared by all requests the factory is working on.
+struct FactoryWorker {
     mod_host_client:
         pmx::mod_host::mod_host_proxy_client::ModHostProxyClient<tonic::transport::Channel>,
     registry_client: pmx::pmx_registry_client::PmxRegistryClient<tonic::transport::Channel>,
     pipewire_client: pmx::pipewire::pipewire_client::PipewireClient<tonic::transport::Channel>,
     config: fr_pmx_config_lib::FactoryConfig,
-    next_channel_strip_id: u32,
     logger: Logger,
+    locks: ObjectLocks,
+    /// Held shared by everything that creates plugins and exclusively by the
+    /// garbage collection, which would take unregistered plugins for orphans.
+    builds: RwLock<()>,
+    journal: Arc<Journal>,
 }
 
 impl Factory {
     pub fn new(
-        receiver: tokio::sync::mpsc::UnboundedReceiver<FactoryRequest>,
+        receiver: tokio::sync::mpsc::Receiver<FactoryRequest>,
         mod_host_client: pmx::mod_host::mod_host_proxy_client::ModHostProxyClient<
             tonic::transport::Channel,
         >,
         registry_client: pmx::pmx_registry_client::PmxRegistryClient<tonic::transport::Channel>,
         pipewire_client: pmx::pipewire::pipewire_client::PipewireClient<tonic::transport::Channel>,
         config: fr_pmx_config_lib::FactoryConfig,
+        journal: Journal,
         logger: Logger,
     ) -> Self {
+        let max_concurrent_requests = config.request_handling.max_concurrent_requests.max(1) as u32;
         Factory {
             receiver,
-            mod_host_client,
-            registry_client,
-            pipewire_client,
-            config,
-            next_channel_strip_id: 0,
-            logger,
+            worker: Arc::new(FactoryWorker {
+                mod_host_client,
+                registry_client,
+                pipewire_client,
+                config,
+                logger,
+                locks: ObjectLocks::default(),
+                builds: RwLock::default(),
+                journal: Arc::new(journal),
+            }),
+            request_permits: Arc::new(Semaphore::new(max_concurrent_requests as usize)),
+            max_concurrent_requests,
         }
     }
 
+    /// Takes requests off the channel and handles each of them in its own
+    /// task, with at most the configured number of requests in flight. Once
+    /// the channel is closed it returns after the requests in flight are done.
+    /// Operations an earlier run left unfinished are rolled back alongside.
     pub async fn run(&mut self) {
-        self.logger.log_info("Starting factory run loop");
+        let logger = &self.worker.logger;
+        tokio::spawn({
+            let worker = self.worker.clone();
+            async move { worker.roll_back_interrupted_operations().await }
+        });
+        logger.log_info("Starting factory run loop");
         loop {
-            let request = self.receiver.recv().await.unwrap();
-            self.logger.log_info("Processing request");
-            match request {
-                FactoryRequest::CreateChannelStrip {
-                    name,
-                    response_sender,
-                    channel_type,
-                } => {
-                    let (id, plugins) = self
-                        .create_and_register_channel_strip(name.clone(), channel_type)
-                        .await;
-                    response_sender
-                        .send(CreateChannelStripResponse {
-                            id,
-                            name: name.clone(),
-                            cross_fader: plugins.cross_fader,
-                            saturator: plugins.saturator,
-                            compressor: plugins.compressor,
-                            equalizer: plugins.equalizer,
-                            gain: plugins.gain,
+            let Some(request) = self.receiver.recv().await else {
+                logger.log_info("Request channel closed, waiting for requests in flight");
+                let _ = self
+                    .request_permits
+                    .acquire_many(self.max_concurrent_requests)
+                    .await;
+                logger.log_info("Stopping factory run loop");
+                return;
+            };
+            let Ok(permit) = self.request_permits.clone().acquire_owned().await else {
+                logger.log_error("Request permits closed, stopping factory run loop");
+                return;
+            };
+            let worker = self.worker.clone();
+            tokio::spawn(async move {
+                worker.handle(request).await;
+                drop(permit);
+            });
+        }
+    }
+}
+
+/// Drives the work of a request and cancels it once the client stops waiting
+/// for the response. The work keeps running up to its next safe point, so
+/// everything it created ends up in its transaction and can be rolled back.
+async fn cancel_on_disconnect<T, R>(
+    response_sender: &mut oneshot::Sender<T>,
+    cancellation: &CancellationToken,
+    work: impl Future<Output = R>,
+) -> R {
+    tokio::pin!(work);
+    tokio::select! {
+        result = &mut work => result,
+        _ = response_sender.closed() => {
+            cancellation.cancel();
+            work.await
+        }
+    }
+}
+
+impl FactoryWorker {
+    async fn handle(&self, request: FactoryRequest) {
+        self.logger.log_info("Processing request");
+        match request {
+            FactoryRequest::CreateChannelStrip {
+                name,
+                mut response_sender,
+                channel_type,
+                channel_layout,
+                template,
+                parameters,
+                repair_links,
+            } => {
+                let _build = self.builds.read().await;
+                let cancellation = CancellationToken::new();
+                let mut transaction = match Transaction::begin(
+                    self.journal.clone(),
+                    &format!("create channel strip {name}"),
+                    cancellation.clone(),
+                ) {
+                    Ok(transaction) => transaction,
+                    Err(error) => return self.respond(response_sender, Err(error)),
+                };
+                let result = cancel_on_disconnect(&mut response_sender, &cancellation, async {
+                    let definition = self.channel_strip_definition(
+                        channel_type,
+                        channel_layout,
+                        template.as_deref(),
+                        parameters,
+                    )?;
+                    let plugins = self
+                        .build_channel_strip(definition, &mut transaction)
+                        .await?;
+                    // The links are verified before the strip is registered,
+                    // so it only shows up in the registry once its wiring
+                    // checked out.
+                    let missing_links = self.verify_links(&mut transaction, repair_links).await?;
+                    let id = self
+                        .record_channel_strip(
+                            name.clone(),
                             channel_type,
-                        })
-                        .unwrap();
-                }
-                FactoryRequest::CreateOutputStage {
-                    name,
-                    response_sender,
-                } => {
-                    let (left_id, left_plugins) = self
-                        .create_and_register_channel_strip(
-                            String::from("Left Stage"),
-                            PmxChannelStripType::Basic,
+                            &plugins,
+                            &mut transaction,
                         )
-                        .await;
-                    let (right_id, right_plugins) = self
-                        .create_and_register_channel_strip(
-                            String::from("Right Stage"),
-                            PmxChannelStripType::Basic,
-                        )
-                        .await;
-                    let cross_fader_plugin = utils::create_plugin(
-                        self.config.channel_strip.cross_fader_plugin_url.clone(),
+                        .await?;
+                    Ok(CreateChannelStripResponse {
+                        id,
+                        name: name.clone(),
+                        cross_fader: plugins.cross_fader,
+                        slots: plugins.slots,
+                        channel_type,
+                        channel_layout,
+                        template,
+                        missing_links,
+                    })
+                })
+                .await;
+                let response = self.commit_or_roll_back(result, transaction).await;
+                self.respond(response_sender, response);
+            }
+            FactoryRequest::CreateOutputStage {
+                name,
+                mut response_sender,
+                repair_links,
+            } => {
+                let _build = self.builds.read().await;
+                let cancellation = CancellationToken::new();
+                let mut transaction = match Transaction::begin(
+                    self.journal.clone(),
+                    &format!("create output stage {name}"),
+                    cancellation.clone(),
+                ) {
+                    Ok(transaction) => transaction,
+                    Err(error) => return self.respond(response_sender, Err(error)),
+                };
+                let result = cancel_on_disconnect(&mut response_sender, &cancellation, async {
+                    self.create_output_stage(name, repair_links, &mut transaction)
+                        .await
+                })
+                .await;
+                let response = self.commit_or_roll_back(result, transaction).await;
+                self.respond(response_sender, response);
+            }
+            FactoryRequest::DeleteChannelStrip {
+                id,
+                response_sender,
+            } => {
+                let response = self.delete_channel_strip(id).await;
+                if let Err(error) = &response {
+                    self.logger
+                        .log_error(&format!("Failed to delete channel strip {id}: {error}"));
+                }
+                self.respond(response_sender, response);
+            }
+            FactoryRequest::DeleteOutputStage {
+                id,
+                response_sender,
+            } => {
+                let response = self.delete_output_stage(id).await;
+                if let Err(error) = &response {
+                    self.logger
+                        .log_error(&format!("Failed to delete output stage {id}: {error}"));
+                }
+                self.respond(response_sender, response);
+            }
+            FactoryRequest::ListTemplates { response_sender } => {
+                let templates = self.config.channel_strip.templates.clone();
+                self.respond(response_sender, Ok(templates));
+            }
+            FactoryRequest::Reconcile {
+                repair,
+                response_sender,
+            } => {
+                let response = self.reconcile(repair).await;
+                if let Err(error) = &response {
+                    self.logger
+                        .log_error(&format!("Failed to reconcile: {error}"));
+                }
+                self.respond(response_sender, response);
+            }
+            FactoryRequest::CollectGarbage {
+                confirm,
+                response_sender,
+            } => {
+                let response = self.collect_garbage(confirm).await;
+                if let Err(error) = &response {
+                    self.logger
+                        .log_error(&format!("Failed to collect garbage: {error}"));
+                }
+                self.respond(response_sender, response);
+            }
+            FactoryRequest::RestoreFromRegistry { response_sender } => {
+                // Plugins that are still alive are kept, so restoring a mixer
+                // that is up does not duplicate it.
+                self.logger.log_info("Restoring mixer from registry");
+                let response = self.reconcile(true).await;
+                if let Err(error) = &response {
+                    self.logger
+                        .log_error(&format!("Failed to restore from registry: {error}"));
+                }
+                self.respond(response_sender, response);
+            }
+        }
+    }
+
+    fn respond<T>(
+        &self,
+        response_sender: oneshot::Sender<Result<T, FactoryError>>,
+        response: Result<T, FactoryError>,
+    ) {
+        if response_sender.send(response).is_err() {
+            self.logger
+                .log_error("Client went away before the response was sent");
+        }
+    }
+
+    /// Commits the transaction of a successful build and rolls back a failed
+    /// one. A build that cannot be committed to the journal is rolled back
+    /// too, otherwise the next start would tear it down behind the client's back.
+    async fn commit_or_roll_back<T>(
+        &self,
+        result: Result<T, FactoryError>,
+        transaction: Transaction,
+    ) -> Result<T, FactoryError> {
+        let result = result.and_then(|value| transaction.commit().map(|_| value));
+        if let Err(error) = &result {
+            self.logger
+                .log_error(&format!("Factory request failed: {error}"));
+            if transaction
+                .rollback(
+                    self.mod_host_client.clone(),
+                    self.pipewire_client.clone(),
+                    self.registry_client.clone(),
+                    &self.config,
+                    &self.logger,
+                )
+                .await
+                .is_err()
+            {
+                self.logger.log_error(
+                    "The remains of the failed request are rolled back on the next start",
+                );
+            }
+        }
+        result
+    }
+
+    /// Undoes the builds an earlier run of the factory did not get to commit
+    /// or roll back. They are not finished, as the clients that asked for them
+    /// are gone. The services the factory depends on may come up after the
+    /// factory, so operations that cannot be undone yet are retried with
+    /// backoff.
+    async fn roll_back_interrupted_operations(&self) {
+        let mut pending: Vec<Transaction> = self
+            .journal
+            .take_interrupted()
+            .into_iter()
+            .map(|operation| {
+                self.logger.log_info(&format!(
+                    "Rolling back interrupted operation {}: {}",
+                    operation.id, operation.description
+                ));
+                Transaction::resume(self.journal.clone(), operation)
+            })
+            .collect();
+        let mut backoff = ROLLBACK_RETRY_INITIAL_BACKOFF;
+        while !pending.is_empty() {
+            let mut remaining = Vec::new();
+            for transaction in pending {
+                let result = transaction
+                    .rollback(
                         self.mod_host_client.clone(),
+                        self.pipewire_client.clone(),
+                        self.registry_client.clone(),
+                        &self.config,
                         &self.logger,
                     )
                     .await;
-                    utils::connect_cross_fader_left(
-                        &left_plugins.gain,
-                        &cross_fader_plugin,
+                if let Err(transaction) = result {
+                    remaining.push(transaction);
+                }
+            }
+            if remaining.is_empty() {
+                break;
+            }
+            self.logger.log_error(&format!(
+                "{} interrupted operations are not rolled back yet, retrying in {backoff:?}",
+                remaining.len()
+            ));
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(ROLLBACK_RETRY_MAX_BACKOFF);
+            pending = remaining;
+        }
+    }
+
+    async fn create_output_stage(
+        &self,
+        name: String,
+        repair_links: bool,
+        transaction: &mut Transaction,
+    ) -> Result<CreateOutputStageResponse, FactoryError> {
+        let definition = self.channel_strip_definition(
+            PmxChannelStripType::Basic,
+            PmxChannelLayout::Stereo,
+            None,
+            Vec::new(),
+        )?;
+        let mut left_transaction = transaction.branch();
+        let mut right_transaction = transaction.branch();
+        let mut cross_fader_transaction = transaction.branch();
+        let channel_type = definition.channel_type;
+        let (left, right, cross_fader_plugin) = tokio::join!(
+            self.build_channel_strip(definition.clone(), &mut left_transaction),
+            self.build_channel_strip(definition, &mut right_transaction),
+            utils::create_plugin(
+                self.config.channel_strip.cross_fader_plugin_url.clone(),
+                &self.config,
+                self.mod_host_client.clone(),
+                &mut cross_fader_transaction,
+                &self.logger,
+            ),
+        );
+        transaction.merge(left_transaction);
+        transaction.merge(right_transaction);
+        transaction.merge(cross_fader_transaction);
+        let left_plugins = left?;
+        let right_plugins = right?;
+        let cross_fader_plugin = cross_fader_plugin?;
+
+        utils::connect_cross_fader_left(
+            left_plugins.output()?,
+            &cross_fader_plugin,
+            &self.config,
+            self.pipewire_client.clone(),
+            transaction,
+            &self.logger,
+        )
+        .await?;
+        utils::connect_cross_fader_right(
+            right_plugins.output()?,
+            &cross_fader_plugin,
+            &self.config,
+            self.pipewire_client.clone(),
+            transaction,
+            &self.logger,
+        )
+        .await?;
+        // The links of the stage and its strips are verified before any of
+        // them is registered, so they only show up in the registry once their
+        // wiring checked out.
+        let missing_links = self.verify_links(transaction, repair_links).await?;
+        let left_id = self
+            .record_channel_strip(
+                String::from("Left Stage"),
+                channel_type,
+                &left_plugins,
+                transaction,
+            )
+            .await?;
+        let right_id = self
+            .record_channel_strip(
+                String::from("Right Stage"),
+                channel_type,
+                &right_plugins,
+                transaction,
+            )
+            .await?;
+
+        transaction.ensure_not_cancelled()?;
+        let registry_request = RegisterOutputStageRequest {
+            name: name.clone(),
+            left_channel_strip_id: left_id,
+            right_channel_strip_id: right_id,
+            cross_fader_plugin_id: cross_fader_plugin.id,
+        };
+
+        let registration = downstream::call(
+            CallPolicy::once(&self.config.downstream.registry),
+            "registry register output stage",
+            &self.logger,
+            || {
+                let mut client = self.registry_client.clone();
+                let request = registry_request.clone();
+                async move { client.register_output_stage(request).await }
+            },
+        )
+        .await
+        .map_err(FactoryError::Registry)?;
+        transaction.record_output_stage_registration(registration.id)?;
+
+        Ok(CreateOutputStageResponse {
+            id: registration.id,
+            name,
+            left_channel_strip_id: left_id,
+            right_channel_strip_id: right_id,
+            cross_fader_plugin_id: cross_fader_plugin.id,
+            missing_links,
+        })
+    }
+
+    async fn verify_links(
+        &self,
+        transaction: &mut Transaction,
+        repair: bool,
+    ) -> Result<Vec<LinkDiscrepancy>, FactoryError> {
+        verification::verify_links(
+            transaction,
+            repair,
+            &self.config,
+            self.pipewire_client.clone(),
+            &self.logger,
+        )
+        .await
+    }
+
+    fn channel_strip_definition(
+        &self,
+        channel_type: PmxChannelStripType,
+        layout: PmxChannelLayout,
+        template: Option<&str>,
+        parameters: Vec<ParameterPreset>,
+    ) -> Result<ChannelStripDefinition, FactoryError> {
+        let mut slots = templates::resolve_slots(&self.config, template)?;
+        templates::apply_presets(&mut slots, parameters)?;
+        Ok(ChannelStripDefinition {
+            channel_type,
+            layout,
+            slots,
+        })
+    }
+
+    async fn build_channel_strip(
+        &self,
+        definition: ChannelStripDefinition,
+        transaction: &mut Transaction,
+    ) -> Result<ChannelStripPlugins, FactoryError> {
+        create_channel_strip(
+            definition,
+            &self.config,
+            self.mod_host_client.clone(),
+            self.pipewire_client.clone(),
+            transaction,
+            &self.logger,
+        )
+        .await
+    }
+
+    /// Registers a built channel strip and records the registration, so a
+    /// rollback removes it again.
+    async fn record_channel_strip(
+        &self,
+        name: String,
+        channel_type: PmxChannelStripType,
+        plugins: &ChannelStripPlugins,
+        transaction: &mut Transaction,
+    ) -> Result<u32, FactoryError> {
+        transaction.ensure_not_cancelled()?;
+        let id = self
+            .register_channel_strip(name, channel_type, plugins, self.registry_client.clone())
+            .await?;
+        transaction.record_channel_strip_registration(id)?;
+        Ok(id)
+    }
+
+    async fn delete_channel_strip(&self, id: u32) -> Result<(), FactoryError> {
+        self.logger
+            .log_info(&format!("Deleting channel strip {id}"));
+        let _lock = self.locks.lock(ObjectKey::ChannelStrip(id)).await;
+        let channel_strip = self.get_channel_strip(id).await?;
+        // The strips of an output stage go together with the stage, deleting
+        // one alone would leave the stage pointing at a strip that is gone.
+        let output_stages = self.list_output_stages().await?;
+        if let Some(output_stage) = output_stages.iter().find(|output_stage| {
+            output_stage.left_channel_strip_id == id || output_stage.right_channel_strip_id == id
+        }) {
+            return Err(FactoryError::FailedPrecondition(format!(
+                "channel strip {id} belongs to output stage {}, delete the output stage instead",
+                output_stage.id
+            )));
+        }
+        self.remove_channel_strip(&channel_strip).await
+    }
+
+    async fn delete_output_stage(&self, id: u32) -> Result<(), FactoryError> {
+        self.logger.log_info(&format!("Deleting output stage {id}"));
+        let _lock = self.locks.lock(ObjectKey::OutputStage(id)).await;
+        let output_stage = self.get_output_stage(id).await?;
+        // The strips are locked before they are read, so their plugins cannot
+        // change in between.
+        let _left_lock = self
+            .locks
+            .lock(ObjectKey::ChannelStrip(output_stage.left_channel_strip_id))
+            .await;
+        let _right_lock = self
+            .locks
+            .lock(ObjectKey::ChannelStrip(output_stage.right_channel_strip_id))
+            .await;
+        let left_channel_strip = self
+            .get_channel_strip(output_stage.left_channel_strip_id)
+            .await?;
+        let right_channel_strip = self
+            .get_channel_strip(output_stage.right_channel_strip_id)
+            .await?;
+
+        let instances =
+            utils::list_plugins(&self.config, self.mod_host_client.clone(), &self.logger).await?;
+        match utils::find_plugin(&instances, output_stage.cross_fader_plugin_id) {
+            Some(cross_fader) => {
+                if let Some(left_output) = channel_strip_output_plugin_id(&left_channel_strip)
+                    .and_then(|id| utils::find_plugin(&instances, id))
+                {
+                    utils::disconnect_cross_fader_left(
+                        left_output,
+                        cross_fader,
+                        &self.config,
                         self.pipewire_client.clone(),
                         &self.logger,
                     )
                     .await;
-                    utils::connect_cross_fader_right(
-                        &right_plugins.gain,
-                        &cross_fader_plugin,
+                }
+                if let Some(right_output) = channel_strip_output_plugin_id(&right_channel_strip)
+                    .and_then(|id| utils::find_plugin(&instances, id))
+                {
+                    utils::disconnect_cross_fader_right(
+                        right_output,
+                        cross_fader,
+                        &self.config,
                         self.pipewire_client.clone(),
                         &self.logger,
                     )
                     .await;
-
-                    let registry_request = RegisterOutputStageRequest {
-                        name: name.clone(),
-                        left_channel_strip_id: left_id,
-                        right_channel_strip_id: right_id,
-                        cross_fader_plugin_id: cross_fader_plugin.id,
-                    };
-
-                    let registry_response = self
-                        .registry_client
-                        .register_output_stage(Request::new(registry_request))
-                        .await
-                        .unwrap();
-
-                    let registration = registry_response.into_inner();
-
-                    response_sender
-                        .send(CreateOutputStageResponse {
-                            id: registration.id,
-                            name: name.clone(),
-                            left_channel_strip_id: left_id,
-                            right_channel_strip_id: right_id,
-                            cross_fader_plugin_id: cross_fader_plugin.id,
-                        })
-                        .unwrap();
                 }
+                utils::remove_plugin(
+                    cross_fader,
+                    &self.config,
+                    self.mod_host_client.clone(),
+                    &self.logger,
+                )
+                .await?;
             }
+            None => self.logger.log_info(&format!(
+                "Cross fader {} is already gone, skipping",
+                output_stage.cross_fader_plugin_id
+            )),
         }
+
+        // The output stage is unregistered before its channel strips, so a
+        // failure below leaves standalone strips that can still be deleted.
+        downstream::call(
+            CallPolicy::once(&self.config.downstream.registry),
+            "registry unregister output stage",
+            &self.logger,
+            || {
+                let mut client = self.registry_client.clone();
+                async move {
+                    client
+                        .unregister_output_stage(UnregisterOutputStageRequest { id })
+                        .await
+                }
+            },
+        )
+        .await
+        .map_err(FactoryError::Registry)?;
+        self.remove_channel_strip(&left_channel_strip).await?;
+        self.remove_channel_strip(&right_channel_strip).await
     }
 
-    async fn create_and_register_channel_strip(
-        &mut self,
-        name: String,
-        channel_type: PmxChannelStripType,
-    ) -> (u32, ChannelStripPlugins) {
-        let id = self.next_channel_strip_id;
-        self.next_channel_strip_id += 1;
-        let plugins = create_channel_strip(
-            channel_type,
-            self.config.clone(),
+    async fn get_channel_strip(&self, id: u32) -> Result<PmxChannelStrip, FactoryError> {
+        downstream::call(
+            CallPolicy::idempotent(&self.config.downstream.registry),
+            "registry get channel strip",
+            &self.logger,
+            || {
+                let mut client = self.registry_client.clone();
+                async move {
+                    client
+                        .get_channel_strip(GetChannelStripRequest { id })
+                        .await
+                }
+            },
+        )
+        .await
+        .map_err(FactoryError::Registry)?
+        .channel_strip
+        .ok_or_else(|| FactoryError::NotFound(format!("channel strip {id}")))
+    }
+
+    async fn get_output_stage(&self, id: u32) -> Result<PmxOutputStage, FactoryError> {
+        downstream::call(
+            CallPolicy::idempotent(&self.config.downstream.registry),
+            "registry get output stage",
+            &self.logger,
+            || {
+                let mut client = self.registry_client.clone();
+                async move { client.get_output_stage(GetOutputStageRequest { id }).await }
+            },
+        )
+        .await
+        .map_err(FactoryError::Registry)?
+        .output_stage
+        .ok_or_else(|| FactoryError::NotFound(format!("output stage {id}")))
+    }
+
+    async fn list_channel_strips(&self) -> Result<Vec<PmxChannelStrip>, FactoryError> {
+        let response = downstream::call(
+            CallPolicy::idempotent(&self.config.downstream.registry),
+            "registry list channel strips",
+            &self.logger,
+            || {
+                let mut client = self.registry_client.clone();
+                async move {
+                    client
+                        .list_channel_strips(ListChannelStripsRequest {})
+                        .await
+                }
+            },
+        )
+        .await
+        .map_err(FactoryError::Registry)?;
+        Ok(response.channel_strips)
+    }
+
+    async fn list_output_stages(&self) -> Result<Vec<PmxOutputStage>, FactoryError> {
+        let response = downstream::call(
+            CallPolicy::idempotent(&self.config.downstream.registry),
+            "registry list output stages",
+            &self.logger,
+            || {
+                let mut client = self.registry_client.clone();
+                async move { client.list_output_stages(ListOutputStagesRequest {}).await }
+            },
+        )
+        .await
+        .map_err(FactoryError::Registry)?;
+        Ok(response.output_stages)
+    }
+
+    async fn remove_channel_strip(
+        &self,
+        channel_strip: &PmxChannelStrip,
+    ) -> Result<(), FactoryError> {
+        delete_channel_strip(
+            channel_strip,
+            &self.config,
             self.mod_host_client.clone(),
             self.pipewire_client.clone(),
             &self.logger,
         )
-        .await;
-        self.register_channel_strip(
-            id,
-            name.clone(),
-            channel_type,
-            &plugins,
-            self.registry_client.clone(),
+        .await?;
+        downstream::call(
+            CallPolicy::once(&self.config.downstream.registry),
+            "registry unregister channel strip",
+            &self.logger,
+            || {
+                let mut client = self.registry_client.clone();
+                let id = channel_strip.id;
+                async move {
+                    client
+                        .unregister_channel_strip(UnregisterChannelStripRequest { id })
+                        .await
+                }
+            },
         )
-        .await;
-        (id, plugins)
+        .await
+        .map_err(FactoryError::Registry)?;
+        Ok(())
     }
 
+    /// Registers the channel strip and returns the ID the registry assigned to it.
     async fn register_channel_strip(
         &self,
-        id: u32,
         name: String,
         channel_type: PmxChannelStripType,
         plugins: &ChannelStripPlugins,
         client: PmxRegistryClient<Channel>,
-    ) {
-        let registry_channel_strip = pmx::channel_strip::PmxChannelStrip {
-            id,
+    ) -> Result<u32, FactoryError> {
+        let registry_channel_strip = PmxChannelStrip {
+            id: 0,
             name,
             channel_strip_type: channel_type as i32,
+            channel_layout: plugins.layout as i32,
             cross_fader_plugin_id: plugins.cross_fader.clone().map(|c| c.id),
-            saturator_plugin_id: plugins.saturator.id,
-            compressor_plugin_id: plugins.compressor.id,
-            equalizer_plugin_id: plugins.equalizer.id,
-            gain_plugin_id: plugins.gain.id,
+            slots: registry_slots(&plugins.slots),
+        };
+        let request = pmx::RegisterChannelStripRequest {
+            channel_strip: Some(registry_channel_strip),
         };
-        let mut client = client;
-        client
-            .register_channel_strip(Request::new(pmx::RegisterChannelStripRequest {
-                channel_strip: Some(registry_channel_strip.clone()),
-            }))
-            .await
-            .unwrap();
+        let registration = downstream::call(
+            CallPolicy::once(&self.config.downstream.registry),
+            "registry register channel strip",
+            &self.logger,
+            || {
+                let mut client = client.clone();
+                let request = request.clone();
+                async move { client.register_channel_strip(request).await }
+            },
+        )
+        .await
+        .map_err(FactoryError::Registry)?;
+        Ok(registration.id)
     }
 }
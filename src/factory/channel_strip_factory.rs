@@ -1,125 +1,266 @@
 use fr_logging::Logger;
-use fr_pmx_config_lib::FactoryConfig;
+use fr_pmx_config_lib::{FactoryConfig, ParameterConfig, PluginSlotConfig};
+use futures_util::future::join_all;
+use itertools::Itertools;
 use tonic::transport::Channel;
 
 use super::{
+    error::FactoryError,
     pmx::{
-        channel_strip::PmxChannelStripType,
+        channel_strip::{
+            PmxChannelLayout, PmxChannelStrip, PmxChannelStripSlot, PmxChannelStripType,
+            PmxParameterValue,
+        },
         mod_host::{mod_host_proxy_client::ModHostProxyClient, plugins::PmxPlugin},
         pipewire::pipewire_client::PipewireClient,
     },
-    utils::{connect_plugins, create_plugin},
+    transaction::Transaction,
+    utils::{
+        connect_plugins, create_plugin, disconnect_plugins, find_plugin, list_plugins,
+        remove_plugin, set_parameters,
+    },
 };
 
+/// Everything needed to build a channel strip.
+#[derive(Debug, Clone)]
+pub struct ChannelStripDefinition {
+    pub channel_type: PmxChannelStripType,
+    pub layout: PmxChannelLayout,
+    pub slots: Vec<PluginSlotConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChannelStripSlot {
+    pub name: String,
+    pub plugin_uri: String,
+    pub plugin: PmxPlugin,
+    pub parameters: Vec<ParameterConfig>,
+}
+
 pub struct ChannelStripPlugins {
+    pub layout: PmxChannelLayout,
     pub cross_fader: Option<PmxPlugin>,
-    pub saturator: PmxPlugin,
-    pub compressor: PmxPlugin,
-    pub equalizer: PmxPlugin,
-    pub gain: PmxPlugin,
+    pub slots: Vec<ChannelStripSlot>,
+}
+
+impl ChannelStripPlugins {
+    /// All plugins of the strip in signal flow order.
+    pub fn chain(&self) -> Vec<&PmxPlugin> {
+        self.cross_fader
+            .iter()
+            .chain(self.slots.iter().map(|slot| &slot.plugin))
+            .collect()
+    }
+
+    /// The plugin whose outputs carry the processed signal of the channel strip.
+    pub fn output(&self) -> Result<&PmxPlugin, FactoryError> {
+        self.chain()
+            .last()
+            .copied()
+            .ok_or_else(|| FactoryError::Internal(String::from("channel strip has no plugins")))
+    }
 }
 
 pub async fn create_channel_strip(
-    channel_type: PmxChannelStripType,
-    config: FactoryConfig,
+    definition: ChannelStripDefinition,
+    config: &FactoryConfig,
+    mod_host_client: ModHostProxyClient<Channel>,
+    pipewire_client: PipewireClient<Channel>,
+    transaction: &mut Transaction,
+    logger: &Logger,
+) -> Result<ChannelStripPlugins, FactoryError> {
+    let plugins =
+        create_channel_strip_plugins(definition, config, mod_host_client, transaction, logger)
+            .await?;
+    connect_channel_internals(&plugins, config, pipewire_client, transaction, logger).await?;
+    Ok(plugins)
+}
+
+/// Tears down a registered channel strip: unlinks its plugins and removes them
+/// from mod-host. Plugins that no longer exist in mod-host are skipped.
+pub async fn delete_channel_strip(
+    channel_strip: &PmxChannelStrip,
+    config: &FactoryConfig,
     mod_host_client: ModHostProxyClient<Channel>,
     pipewire_client: PipewireClient<Channel>,
     logger: &Logger,
-) -> ChannelStripPlugins {
-    let plugins = create_channel_strip_plugins(channel_type, config, mod_host_client, logger).await;
-    connect_channel_internals(channel_type, &plugins, pipewire_client, logger).await;
-    plugins
+) -> Result<(), FactoryError> {
+    let instances = list_plugins(config, mod_host_client.clone(), logger).await?;
+    let plugin_ids = channel_strip_plugin_ids(channel_strip);
+    let plugins: Vec<Option<PmxPlugin>> = plugin_ids
+        .iter()
+        .map(|id| find_plugin(&instances, *id).cloned())
+        .collect();
+
+    for (output, input) in plugins.iter().tuple_windows() {
+        if let (Some(output), Some(input)) = (output, input) {
+            disconnect_plugins(
+                output,
+                input,
+                channel_strip.channel_layout(),
+                config,
+                pipewire_client.clone(),
+                logger,
+            )
+            .await;
+        }
+    }
+
+    for (id, plugin) in plugin_ids.iter().zip(plugins) {
+        match plugin {
+            Some(plugin) => remove_plugin(&plugin, config, mod_host_client.clone(), logger).await?,
+            None => logger.log_info(&format!("Plugin {id} is already gone, skipping")),
+        }
+    }
+
+    Ok(())
+}
+
+/// The slots of a built channel strip the way the registry stores them.
+pub fn registry_slots(slots: &[ChannelStripSlot]) -> Vec<PmxChannelStripSlot> {
+    slots
+        .iter()
+        .map(|slot| PmxChannelStripSlot {
+            name: slot.name.clone(),
+            plugin_uri: slot.plugin_uri.clone(),
+            plugin_id: slot.plugin.id,
+            parameters: slot
+                .parameters
+                .iter()
+                .map(|parameter| PmxParameterValue {
+                    symbol: parameter.symbol.clone(),
+                    value: parameter.value,
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// The parameters of a registered slot, to set them on a new plugin instance.
+pub fn parameter_configs(parameters: &[PmxParameterValue]) -> Vec<ParameterConfig> {
+    parameters
+        .iter()
+        .map(|parameter| ParameterConfig {
+            symbol: parameter.symbol.clone(),
+            value: parameter.value,
+        })
+        .collect()
+}
+
+/// The plugin whose outputs carry the processed signal of a registered channel strip.
+pub fn channel_strip_output_plugin_id(channel_strip: &PmxChannelStrip) -> Option<u32> {
+    channel_strip_plugin_ids(channel_strip).last().copied()
+}
+
+/// The plugins of a registered channel strip, from its input to its output.
+pub fn channel_strip_plugin_ids(channel_strip: &PmxChannelStrip) -> Vec<u32> {
+    channel_strip
+        .cross_fader_plugin_id
+        .into_iter()
+        .chain(channel_strip.slots.iter().map(|slot| slot.plugin_id))
+        .collect()
 }
 
 async fn connect_channel_internals(
-    channel_type: PmxChannelStripType,
     plugins: &ChannelStripPlugins,
+    config: &FactoryConfig,
     pipewire_client: PipewireClient<Channel>,
+    transaction: &mut Transaction,
     logger: &Logger,
-) {
-    if channel_type == PmxChannelStripType::CrossFaded {
+) -> Result<(), FactoryError> {
+    for (output, input) in plugins.chain().into_iter().tuple_windows() {
         connect_plugins(
-            &plugins.cross_fader.clone().unwrap(),
-            &plugins.saturator,
+            output,
+            input,
+            plugins.layout,
+            config,
             pipewire_client.clone(),
+            transaction,
             logger,
         )
-        .await;
+        .await?;
     }
+    Ok(())
+}
 
-    connect_plugins(
-        &plugins.saturator,
-        &plugins.compressor,
-        pipewire_client.clone(),
-        logger,
-    )
-    .await;
+async fn create_channel_strip_plugins(
+    definition: ChannelStripDefinition,
+    config: &FactoryConfig,
+    client: ModHostProxyClient<Channel>,
+    transaction: &mut Transaction,
+    logger: &Logger,
+) -> Result<ChannelStripPlugins, FactoryError> {
+    let cross_fader_uri = match definition.channel_type {
+        PmxChannelStripType::Basic => None,
+        PmxChannelStripType::CrossFaded => {
+            Some(config.channel_strip.cross_fader_plugin_url.clone())
+        }
+    };
+    let uris: Vec<String> = cross_fader_uri
+        .iter()
+        .cloned()
+        .chain(definition.slots.iter().map(|slot| slot.plugin_url.clone()))
+        .collect();
+    let mut plugins = create_plugins(uris, config, client.clone(), transaction, logger)
+        .await?
+        .into_iter();
 
-    connect_plugins(
-        &plugins.compressor,
-        &plugins.equalizer,
-        pipewire_client.clone(),
-        logger,
-    )
-    .await;
+    let cross_fader = match cross_fader_uri {
+        Some(_) => plugins.next(),
+        None => None,
+    };
+    let mut slots = Vec::with_capacity(definition.slots.len());
+    for (slot, plugin) in definition.slots.into_iter().zip(plugins) {
+        set_parameters(&plugin, &slot.parameters, config, client.clone(), logger).await?;
+        slots.push(ChannelStripSlot {
+            name: slot.name,
+            plugin_uri: slot.plugin_url,
+            plugin,
+            parameters: slot.parameters,
+        });
+    }
 
-    connect_plugins(
-        &plugins.equalizer,
-        &plugins.gain,
-        pipewire_client.clone(),
-        logger,
-    )
-    .await;
+    Ok(ChannelStripPlugins {
+        layout: definition.layout,
+        cross_fader,
+        slots,
+    })
 }
 
-async fn create_channel_strip_plugins(
-    channel_type: PmxChannelStripType,
-    config: FactoryConfig,
+/// Instantiates all plugins at once. Every instantiation is awaited, even
+/// after one failed, so that all instances that did get created end up in the
+/// transaction and can be rolled back.
+async fn create_plugins(
+    uris: Vec<String>,
+    config: &FactoryConfig,
     client: ModHostProxyClient<Channel>,
+    transaction: &mut Transaction,
     logger: &Logger,
-) -> ChannelStripPlugins {
-    let clones = (
-        client.clone(),
-        client.clone(),
-        client.clone(),
-        client.clone(),
-        client.clone(),
-    );
-    ChannelStripPlugins {
-        cross_fader: match channel_type {
-            PmxChannelStripType::Basic => None,
-            PmxChannelStripType::CrossFaded => Some(
-                create_plugin(
-                    config.channel_strip.cross_fader_plugin_url.clone(),
-                    clones.0,
-                    logger,
-                )
-                .await,
-            ),
-        },
-        saturator: create_plugin(
-            config.channel_strip.saturator_plugin_url.clone(),
-            clones.1,
-            logger,
-        )
-        .await,
-        compressor: create_plugin(
-            config.channel_strip.cross_fader_plugin_url.clone(),
-            clones.2,
-            logger,
-        )
-        .await,
-        equalizer: create_plugin(
-            config.channel_strip.equalizer_plugin_url.clone(),
-            clones.3,
-            logger,
-        )
-        .await,
-        gain: create_plugin(
-            config.channel_strip.gain_plugin_url.clone(),
-            clones.4,
-            logger,
-        )
-        .await,
+) -> Result<Vec<PmxPlugin>, FactoryError> {
+    let results = join_all(uris.into_iter().map(|uri| {
+        let client = client.clone();
+        let mut created = transaction.branch();
+        async move {
+            let result = create_plugin(uri, config, client, &mut created, logger).await;
+            (result, created)
+        }
+    }))
+    .await;
+
+    let mut plugins = Vec::with_capacity(results.len());
+    let mut first_error = None;
+    for (result, created) in results {
+        transaction.merge(created);
+        match result {
+            Ok(plugin) => plugins.push(plugin),
+            Err(error) => {
+                first_error.get_or_insert(error);
+            }
+        }
+    }
+
+    match first_error {
+        Some(error) => Err(error),
+        None => Ok(plugins),
     }
 }
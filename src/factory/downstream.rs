@@ -0,0 +1,84 @@
+use std::future::Future;
+use std::time::Duration;
+
+use fr_logging::Logger;
+use fr_pmx_config_lib::DownstreamConfig;
+use tonic::{Code, Response, Status};
+
+/// How a call to a downstream service is attempted.
+#[derive(Debug, Clone, Copy)]
+pub struct CallPolicy {
+    timeout: Duration,
+    attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl CallPolicy {
+    /// Retries transient failures with exponential backoff. Only for calls
+    /// that leave the same state behind when they are repeated.
+    pub fn idempotent(config: &DownstreamConfig) -> Self {
+        CallPolicy {
+            timeout: Duration::from_millis(config.timeout_ms),
+            attempts: config.max_attempts.max(1),
+            initial_backoff: Duration::from_millis(config.initial_backoff_ms),
+            max_backoff: Duration::from_millis(config.max_backoff_ms),
+        }
+    }
+
+    /// A single attempt, for calls that must not be repeated.
+    pub fn once(config: &DownstreamConfig) -> Self {
+        CallPolicy {
+            attempts: 1,
+            ..CallPolicy::idempotent(config)
+        }
+    }
+}
+
+/// Calls a downstream service with the timeout and retries of the policy.
+/// Every attempt is logged.
+pub async fn call<T, F, Fut>(
+    policy: CallPolicy,
+    description: &str,
+    logger: &Logger,
+    mut call: F,
+) -> Result<T, Status>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Response<T>, Status>>,
+{
+    let mut backoff = policy.initial_backoff;
+    let mut attempt = 1;
+    loop {
+        logger.log_info(&format!(
+            "Calling {description}, attempt {attempt} of {}",
+            policy.attempts
+        ));
+        let status = match tokio::time::timeout(policy.timeout, call()).await {
+            Ok(Ok(response)) => return Ok(response.into_inner()),
+            Ok(Err(status)) => status,
+            Err(_) => Status::deadline_exceeded(format!(
+                "{description} timed out after {:?}",
+                policy.timeout
+            )),
+        };
+        if attempt >= policy.attempts || !is_transient(&status) {
+            logger.log_error(&format!(
+                "{description} failed on attempt {attempt}: {}",
+                status.message()
+            ));
+            return Err(status);
+        }
+        logger.log_error(&format!(
+            "{description} failed on attempt {attempt}: {}, retrying in {backoff:?}",
+            status.message()
+        ));
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(policy.max_backoff);
+        attempt += 1;
+    }
+}
+
+fn is_transient(status: &Status) -> bool {
+    matches!(status.code(), Code::Unavailable | Code::DeadlineExceeded)
+}
@@ -0,0 +1,32 @@
+use std::fmt;
+
+use tonic::Status;
+
+#[derive(Debug)]
+pub enum FactoryError {
+    ModHost(Status),
+    Pipewire(Status),
+    Registry(Status),
+    Validation(String),
+    NotFound(String),
+    FailedPrecondition(String),
+    Internal(String),
+    Cancelled,
+}
+
+impl fmt::Display for FactoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FactoryError::ModHost(status) => write!(f, "mod host: {}", status.message()),
+            FactoryError::Pipewire(status) => write!(f, "pipewire: {}", status.message()),
+            FactoryError::Registry(status) => write!(f, "registry: {}", status.message()),
+            FactoryError::Validation(message) => write!(f, "invalid request: {message}"),
+            FactoryError::NotFound(message) => write!(f, "not found: {message}"),
+            FactoryError::FailedPrecondition(message) => write!(f, "{message}"),
+            FactoryError::Internal(message) => write!(f, "internal error: {message}"),
+            FactoryError::Cancelled => write!(f, "request cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for FactoryError {}
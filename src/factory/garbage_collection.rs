@@ -0,0 +1,196 @@
+use std::collections::HashSet;
+
+use tonic::Code;
+
+use super::channel_strip_factory::channel_strip_plugin_ids;
+use super::downstream::{self, CallPolicy};
+use super::error::FactoryError;
+use super::pmx::{
+    channel_strip::PmxChannelStrip, mod_host::plugins::PmxPlugin, ListInputsRequest,
+    ListLoopersRequest, ListOutputsRequest, ListPluginsRequest,
+};
+use super::ports::list_nodes;
+use super::transaction::PluginLink;
+use super::verification::list_links;
+use super::{utils, FactoryWorker};
+
+/// A mod-host plugin instance of a kind the factory builds that belongs to no
+/// registered channel strip or output stage.
+#[derive(Debug, Clone)]
+pub struct OrphanedPlugin {
+    pub plugin: PmxPlugin,
+    pub removed: bool,
+}
+
+impl FactoryWorker {
+    /// Finds the plugin instances no registry entry refers to and, only when
+    /// `confirm` is set, removes them together with their links. Builds hold
+    /// their plugins before they are registered, so the collection waits for
+    /// the builds in flight and keeps new ones out until it is done.
+    ///
+    /// Besides the channel strips and output stages, the registry holds the
+    /// plugins, inputs, outputs and loopers other pmx services registered,
+    /// and the instances they refer to are kept as well. As a second guard
+    /// only instances of the plugin URIs the factory builds channel strips
+    /// and output stages from are considered, as the factory cannot tell
+    /// whether an instance of any other plugin is still in use.
+    pub(super) async fn collect_garbage(
+        &self,
+        confirm: bool,
+    ) -> Result<Vec<OrphanedPlugin>, FactoryError> {
+        let _builds = self.builds.write().await;
+        self.logger.log_info("Collecting orphaned plugins");
+        let channel_strips = self.list_channel_strips().await?;
+        let output_stages = self.list_output_stages().await?;
+        let mut registered: HashSet<u32> = channel_strips
+            .iter()
+            .flat_map(channel_strip_plugin_ids)
+            .chain(
+                output_stages
+                    .iter()
+                    .map(|output_stage| output_stage.cross_fader_plugin_id),
+            )
+            .collect();
+        registered.extend(self.other_registered_ids().await?);
+        let factory_uris = self.factory_plugin_uris(&channel_strips);
+        let instances =
+            utils::list_plugins(&self.config, self.mod_host_client.clone(), &self.logger).await?;
+        let orphans: Vec<PmxPlugin> = instances
+            .into_iter()
+            .filter(|plugin| factory_uris.contains(&plugin.plugin_uri))
+            .filter(|plugin| !registered.contains(&plugin.id))
+            .collect();
+        self.logger
+            .log_info(&format!("Found {} orphaned plugins", orphans.len()));
+        if !confirm {
+            return Ok(orphans
+                .into_iter()
+                .map(|plugin| OrphanedPlugin {
+                    plugin,
+                    removed: false,
+                })
+                .collect());
+        }
+
+        let nodes = list_nodes(&self.config, self.pipewire_client.clone(), &self.logger).await?;
+        let links = list_links(&self.config, self.pipewire_client.clone(), &self.logger).await?;
+        let node_name = |id: u32| {
+            nodes
+                .iter()
+                .find(|node| node.id == id)
+                .map(|node| node.name.clone())
+        };
+        let orphan_nodes: HashSet<u32> = nodes
+            .iter()
+            .filter(|node| orphans.iter().any(|plugin| plugin.name == node.name))
+            .map(|node| node.id)
+            .collect();
+
+        // A link that cannot be removed goes away together with its plugin.
+        for link in links.iter().filter(|link| {
+            orphan_nodes.contains(&link.output_node_id)
+                || orphan_nodes.contains(&link.input_node_id)
+        }) {
+            let (Some(output_node_name), Some(input_node_name)) = (
+                node_name(link.output_node_id),
+                node_name(link.input_node_id),
+            ) else {
+                continue;
+            };
+            let link = PluginLink {
+                output_node_name,
+                output_port_id: link.output_port_id,
+                input_node_name,
+                input_port_id: link.input_port_id,
+            };
+            utils::remove_link(&link, &self.config, &self.pipewire_client, &self.logger).await;
+        }
+
+        let mut collected = Vec::with_capacity(orphans.len());
+        for plugin in orphans {
+            let removed = match utils::remove_plugin(
+                &plugin,
+                &self.config,
+                self.mod_host_client.clone(),
+                &self.logger,
+            )
+            .await
+            {
+                Ok(()) => true,
+                // Deleted by a request that ran alongside the collection.
+                Err(FactoryError::ModHost(status)) if status.code() == Code::NotFound => true,
+                Err(error) => {
+                    self.logger.log_error(&format!(
+                        "Failed to remove orphaned plugin {}: {error}",
+                        plugin.id
+                    ));
+                    false
+                }
+            };
+            collected.push(OrphanedPlugin { plugin, removed });
+        }
+        Ok(collected)
+    }
+
+    /// The IDs of the registry entries other pmx services own. An entry that
+    /// is not backed by a plugin instance at worst keeps an orphan with the
+    /// same ID alive, which is the safe way to be wrong.
+    async fn other_registered_ids(&self) -> Result<Vec<u32>, FactoryError> {
+        let registry = CallPolicy::idempotent(&self.config.downstream.registry);
+        let plugins = downstream::call(registry, "registry list plugins", &self.logger, || {
+            let mut client = self.registry_client.clone();
+            async move { client.list_plugins(ListPluginsRequest {}).await }
+        })
+        .await
+        .map_err(FactoryError::Registry)?
+        .plugins;
+        let inputs = downstream::call(registry, "registry list inputs", &self.logger, || {
+            let mut client = self.registry_client.clone();
+            async move { client.list_inputs(ListInputsRequest {}).await }
+        })
+        .await
+        .map_err(FactoryError::Registry)?
+        .inputs;
+        let outputs = downstream::call(registry, "registry list outputs", &self.logger, || {
+            let mut client = self.registry_client.clone();
+            async move { client.list_outputs(ListOutputsRequest {}).await }
+        })
+        .await
+        .map_err(FactoryError::Registry)?
+        .outputs;
+        let loopers = downstream::call(registry, "registry list loopers", &self.logger, || {
+            let mut client = self.registry_client.clone();
+            async move { client.list_loopers(ListLoopersRequest {}).await }
+        })
+        .await
+        .map_err(FactoryError::Registry)?
+        .loopers;
+        Ok(plugins
+            .iter()
+            .map(|plugin| plugin.id)
+            .chain(inputs.iter().map(|input| input.id))
+            .chain(outputs.iter().map(|output| output.id))
+            .chain(loopers.iter().map(|looper| looper.id))
+            .collect())
+    }
+
+    /// The URIs of every plugin the factory creates: the cross fader, the
+    /// slots of the default strip and of the templates, and the slots of the
+    /// registered strips, which may predate a change of the configuration.
+    fn factory_plugin_uris(&self, channel_strips: &[PmxChannelStrip]) -> HashSet<String> {
+        let channel_strip_config = &self.config.channel_strip;
+        let configured_slots = channel_strip_config.slots.iter().chain(
+            channel_strip_config
+                .templates
+                .iter()
+                .flat_map(|template| template.slots.iter()),
+        );
+        let registered_slots = channel_strips
+            .iter()
+            .flat_map(|channel_strip| channel_strip.slots.iter());
+        std::iter::once(channel_strip_config.cross_fader_plugin_url.clone())
+            .chain(configured_slots.map(|slot| slot.plugin_url.clone()))
+            .chain(registered_slots.map(|slot| slot.plugin_uri.clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,435 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use super::error::FactoryError;
+use super::transaction::{PluginInstance, PluginLink, Resource};
+
+/// An operation that was started but neither committed nor rolled back.
+#[derive(Debug)]
+pub struct Operation {
+    pub id: u64,
+    pub description: String,
+    pub resources: Vec<Resource>,
+}
+
+enum Entry {
+    Begin(String),
+    Created(Resource),
+    End,
+}
+
+/// An append-only log of the resources every factory operation creates. It
+/// is written before the operation moves on, so the resources of an operation
+/// interrupted by a crash can still be found and removed on the next start.
+///
+/// Every line holds one entry as tab separated fields, starting with the
+/// operation ID and the kind of entry. The file is emptied whenever no
+/// operation is open, so it does not grow for the life of the factory.
+#[derive(Debug)]
+pub struct Journal {
+    file: Mutex<JournalFile>,
+    next_operation: AtomicU64,
+    interrupted: Mutex<Vec<Operation>>,
+}
+
+#[derive(Debug)]
+struct JournalFile {
+    file: File,
+    /// The operations that were begun and not ended yet, including the
+    /// interrupted ones of the previous run.
+    open: HashSet<u64>,
+}
+
+impl Journal {
+    /// Opens the journal and collects the operations a previous run left
+    /// unfinished. Finished operations are dropped from the file.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Journal> {
+        let path = path.as_ref();
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error),
+        };
+
+        let mut operations = BTreeMap::new();
+        let mut last_operation = 0;
+        // A crash can cut the last line short, which may still parse, as in a
+        // plugin URI that lost its last characters. Only complete lines are read,
+        // and a line that cannot be parsed is skipped as well.
+        let complete_lines = contents
+            .split_inclusive('\n')
+            .filter_map(|line| line.strip_suffix('\n'));
+        for (id, entry) in complete_lines.filter_map(parse_line) {
+            last_operation = last_operation.max(id);
+            match entry {
+                Entry::Begin(description) => {
+                    operations.insert(
+                        id,
+                        Operation {
+                            id,
+                            description,
+                            resources: Vec::new(),
+                        },
+                    );
+                }
+                Entry::Created(resource) => {
+                    if let Some(operation) = operations.get_mut(&id) {
+                        operation.resources.push(resource);
+                    }
+                }
+                Entry::End => {
+                    operations.remove(&id);
+                }
+            }
+        }
+        let interrupted: Vec<Operation> = operations.into_values().collect();
+
+        let compacted = path.with_extension("tmp");
+        let mut file = File::create(&compacted)?;
+        for operation in &interrupted {
+            let description = Entry::Begin(operation.description.clone());
+            file.write_all(format_line(operation.id, &description).as_bytes())?;
+            for resource in &operation.resources {
+                file.write_all(
+                    format_line(operation.id, &Entry::Created(resource.clone())).as_bytes(),
+                )?;
+            }
+        }
+        file.sync_all()?;
+        fs::rename(&compacted, path)?;
+
+        Ok(Journal {
+            file: Mutex::new(JournalFile {
+                file: OpenOptions::new().append(true).open(path)?,
+                open: interrupted.iter().map(|operation| operation.id).collect(),
+            }),
+            next_operation: AtomicU64::new(last_operation + 1),
+            interrupted: Mutex::new(interrupted),
+        })
+    }
+
+    /// The operations the previous run left unfinished. They are handed out
+    /// only once.
+    pub fn take_interrupted(&self) -> Vec<Operation> {
+        let mut interrupted = self
+            .interrupted
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        std::mem::take(&mut *interrupted)
+    }
+
+    /// Starts a new operation and returns its ID.
+    pub fn begin(&self, description: &str) -> Result<u64, FactoryError> {
+        let id = self.next_operation.fetch_add(1, Ordering::Relaxed);
+        self.append(id, &Entry::Begin(String::from(description)))?;
+        Ok(id)
+    }
+
+    pub fn record(&self, operation: u64, resource: &Resource) -> Result<(), FactoryError> {
+        self.append(operation, &Entry::Created(resource.clone()))
+    }
+
+    /// Marks the operation as committed or completely rolled back.
+    pub fn end(&self, operation: u64) -> Result<(), FactoryError> {
+        self.append(operation, &Entry::End)
+    }
+
+    fn append(&self, operation: u64, entry: &Entry) -> Result<(), FactoryError> {
+        let mut journal = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        journal
+            .file
+            .write_all(format_line(operation, entry).as_bytes())
+            .and_then(|_| journal.file.sync_data())
+            .map_err(|error| FactoryError::Internal(format!("failed to write journal: {error}")))?;
+        match entry {
+            Entry::Begin(_) => {
+                journal.open.insert(operation);
+            }
+            Entry::End => {
+                journal.open.remove(&operation);
+            }
+            Entry::Created(_) => {}
+        }
+        // Nothing in the file is needed anymore. The file is opened for
+        // appending, so the next entry starts at the beginning again. When it
+        // cannot be emptied it only keeps ended operations, which are dropped
+        // on the next open, so the entry itself still counts as written.
+        if journal.open.is_empty() {
+            let _ = journal
+                .file
+                .set_len(0)
+                .and_then(|_| journal.file.sync_data());
+        }
+        Ok(())
+    }
+}
+
+fn format_line(operation: u64, entry: &Entry) -> String {
+    let fields = match entry {
+        Entry::Begin(description) => vec![String::from("begin"), escape(description)],
+        Entry::Created(Resource::Plugin(instance)) => vec![
+            String::from("plugin"),
+            instance.id.to_string(),
+            escape(&instance.name),
+            escape(&instance.plugin_uri),
+        ],
+        Entry::Created(Resource::Link(link)) => vec![
+            String::from("link"),
+            escape(&link.output_node_name),
+            link.output_port_id.to_string(),
+            escape(&link.input_node_name),
+            link.input_port_id.to_string(),
+        ],
+        Entry::Created(Resource::ChannelStripRegistration(id)) => {
+            vec![String::from("channel_strip"), id.to_string()]
+        }
+        Entry::Created(Resource::OutputStageRegistration(id)) => {
+            vec![String::from("output_stage"), id.to_string()]
+        }
+        Entry::End => vec![String::from("end")],
+    };
+    format!("{operation}\t{}\n", fields.join("\t"))
+}
+
+fn parse_line(line: &str) -> Option<(u64, Entry)> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let operation = fields.first()?.parse().ok()?;
+    let entry = match fields[1..] {
+        ["begin", description] => Entry::Begin(unescape(description)),
+        ["plugin", id, name, plugin_uri] => Entry::Created(Resource::Plugin(PluginInstance {
+            id: id.parse().ok()?,
+            name: unescape(name),
+            plugin_uri: unescape(plugin_uri),
+        })),
+        ["link", output_node_name, output_port_id, input_node_name, input_port_id] => {
+            Entry::Created(Resource::Link(PluginLink {
+                output_node_name: unescape(output_node_name),
+                output_port_id: output_port_id.parse().ok()?,
+                input_node_name: unescape(input_node_name),
+                input_port_id: input_port_id.parse().ok()?,
+            }))
+        }
+        ["channel_strip", id] => {
+            Entry::Created(Resource::ChannelStripRegistration(id.parse().ok()?))
+        }
+        ["output_stage", id] => Entry::Created(Resource::OutputStageRegistration(id.parse().ok()?)),
+        ["end"] => Entry::End,
+        _ => return None,
+    };
+    Some((operation, entry))
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut characters = value.chars();
+    while let Some(character) = characters.next() {
+        if character != '\\' {
+            unescaped.push(character);
+            continue;
+        }
+        match characters.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// A journal path of its own for every test, removed again on drop.
+    struct TempJournal(PathBuf);
+
+    impl TempJournal {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "fr-pmx-factory-{}-{name}.journal",
+                std::process::id()
+            ));
+            let _ = fs::remove_file(&path);
+            TempJournal(path)
+        }
+    }
+
+    impl Drop for TempJournal {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+            let _ = fs::remove_file(self.0.with_extension("tmp"));
+        }
+    }
+
+    fn plugin(id: u32) -> Resource {
+        Resource::Plugin(PluginInstance {
+            id,
+            name: format!("effect_{id}"),
+            plugin_uri: String::from("urn:example:gain"),
+        })
+    }
+
+    fn link() -> PluginLink {
+        PluginLink {
+            output_node_name: String::from("effect\tone\\"),
+            output_port_id: 1,
+            input_node_name: String::from("effect\ntwo"),
+            input_port_id: 3,
+        }
+    }
+
+    #[test]
+    fn escape_round_trips_tabs_newlines_and_backslashes() {
+        for value in [
+            "plain",
+            "a\tb",
+            "a\nb",
+            "a\\b",
+            "\\t",
+            "\\\t\n\\",
+            "trailing\\",
+        ] {
+            let escaped = escape(value);
+            assert!(!escaped.contains('\t') && !escaped.contains('\n'));
+            assert_eq!(unescape(&escaped), value);
+        }
+    }
+
+    #[test]
+    fn entries_round_trip_through_a_line() {
+        let line = format_line(7, &Entry::Begin(String::from("create\tchannel strip\nA")));
+        let (operation, entry) = parse_line(line.trim_end_matches('\n')).unwrap();
+        assert_eq!(operation, 7);
+        assert!(
+            matches!(entry, Entry::Begin(description) if description == "create\tchannel strip\nA")
+        );
+
+        let line = format_line(7, &Entry::Created(Resource::Link(link())));
+        let (_, entry) = parse_line(line.trim_end_matches('\n')).unwrap();
+        let Entry::Created(Resource::Link(parsed)) = entry else {
+            panic!("expected a link");
+        };
+        assert_eq!(parsed.output_node_name, link().output_node_name);
+        assert_eq!(parsed.output_port_id, 1);
+        assert_eq!(parsed.input_node_name, link().input_node_name);
+        assert_eq!(parsed.input_port_id, 3);
+
+        let line = format_line(7, &Entry::Created(plugin(4)));
+        let (_, entry) = parse_line(line.trim_end_matches('\n')).unwrap();
+        let Entry::Created(Resource::Plugin(parsed)) = entry else {
+            panic!("expected a plugin");
+        };
+        assert_eq!(parsed.id, 4);
+        assert_eq!(parsed.name, "effect_4");
+        assert_eq!(parsed.plugin_uri, "urn:example:gain");
+
+        let line = format_line(7, &Entry::Created(Resource::OutputStageRegistration(2)));
+        let (_, entry) = parse_line(line.trim_end_matches('\n')).unwrap();
+        assert!(matches!(
+            entry,
+            Entry::Created(Resource::OutputStageRegistration(2))
+        ));
+    }
+
+    #[test]
+    fn truncated_lines_are_skipped() {
+        assert!(parse_line("").is_none());
+        assert!(parse_line("3").is_none());
+        assert!(parse_line("3\tplu").is_none());
+        assert!(parse_line("3\tplugin\t").is_none());
+        assert!(parse_line("3\tplugin\t4\teffect_4").is_none());
+        assert!(parse_line("3\tlink\ta\t1\tb").is_none());
+
+        let journal = TempJournal::new("truncated");
+        fs::write(
+            &journal.0,
+            "1\tbegin\tbuild\n1\tplugin\t4\teffect_4\turn:example:gain\n1\tlink\tout\t0\tin",
+        )
+        .unwrap();
+        let opened = Journal::open(&journal.0).unwrap();
+        let interrupted = opened.take_interrupted();
+        assert_eq!(interrupted.len(), 1);
+        assert_eq!(interrupted[0].resources.len(), 1);
+        assert!(matches!(&interrupted[0].resources[0], Resource::Plugin(p) if p.id == 4));
+        drop(opened);
+
+        // The last entry lost the end of its URI. It still parses, but must
+        // not be taken for an instance of a plugin with a shorter URI.
+        fs::write(
+            &journal.0,
+            "1\tbegin\tbuild\n1\tplugin\t4\teffect_4\turn:example:gain\n1\tplugin\t42\teffect_42\turn:example:g",
+        )
+        .unwrap();
+        let opened = Journal::open(&journal.0).unwrap();
+        let interrupted = opened.take_interrupted();
+        assert_eq!(interrupted.len(), 1);
+        assert_eq!(interrupted[0].resources.len(), 1);
+        assert!(matches!(&interrupted[0].resources[0], Resource::Plugin(p) if p.id == 4));
+    }
+
+    #[test]
+    fn open_keeps_only_interrupted_operations() {
+        let journal = TempJournal::new("compaction");
+        {
+            let first = Journal::open(&journal.0).unwrap();
+            let finished = first.begin("finished").unwrap();
+            first.record(finished, &plugin(1)).unwrap();
+            first.end(finished).unwrap();
+            let interrupted = first.begin("interrupted").unwrap();
+            first.record(interrupted, &plugin(2)).unwrap();
+            first
+                .record(interrupted, &Resource::ChannelStripRegistration(5))
+                .unwrap();
+        }
+
+        let second = Journal::open(&journal.0).unwrap();
+        let interrupted = second.take_interrupted();
+        assert_eq!(interrupted.len(), 1);
+        assert_eq!(interrupted[0].id, 2);
+        assert_eq!(interrupted[0].description, "interrupted");
+        assert!(matches!(
+            interrupted[0].resources[..],
+            [Resource::Plugin(ref p), Resource::ChannelStripRegistration(5)] if p.id == 2
+        ));
+        assert!(second.take_interrupted().is_empty());
+        assert_eq!(second.begin("next").unwrap(), 3);
+
+        let contents = fs::read_to_string(&journal.0).unwrap();
+        assert!(!contents.contains("finished"));
+        assert_eq!(contents.lines().count(), 4);
+    }
+
+    #[test]
+    fn file_is_emptied_once_no_operation_is_open() {
+        let journal = TempJournal::new("emptied");
+        let opened = Journal::open(&journal.0).unwrap();
+        let first = opened.begin("first").unwrap();
+        let second = opened.begin("second").unwrap();
+        opened.record(first, &plugin(1)).unwrap();
+        opened.end(first).unwrap();
+        assert_eq!(fs::read_to_string(&journal.0).unwrap().lines().count(), 4);
+
+        opened.end(second).unwrap();
+        assert!(fs::read_to_string(&journal.0).unwrap().is_empty());
+
+        let third = opened.begin("third").unwrap();
+        opened.record(third, &plugin(3)).unwrap();
+        let contents = fs::read_to_string(&journal.0).unwrap();
+        assert!(contents.starts_with(&format!("{third}\tbegin\tthird\n")));
+        assert_eq!(contents.lines().count(), 2);
+    }
+}
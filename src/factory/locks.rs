@@ -0,0 +1,32 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tokio::sync::OwnedMutexGuard;
+
+/// An object managed by the factory that requests can contend for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjectKey {
+    ChannelStrip(u32),
+    OutputStage(u32),
+}
+
+/// Per-object locks that keep requests touching the same objects in order
+/// while requests on unrelated objects run concurrently.
+#[derive(Debug, Default)]
+pub struct ObjectLocks {
+    locks: Mutex<HashMap<ObjectKey, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl ObjectLocks {
+    /// Waits until no other request holds the object. The object stays locked
+    /// until the returned guard is dropped.
+    pub async fn lock(&self, key: ObjectKey) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+            // Locks only referenced by the map are neither held nor awaited.
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(key).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+}
@@ -0,0 +1,568 @@
+use std::time::Duration;
+
+use fr_logging::Logger;
+use fr_pmx_config_lib::{FactoryConfig, PortMappingConfig};
+use tokio::time::Instant;
+use tonic::transport::Channel;
+use tonic::Status;
+
+use super::downstream::{self, CallPolicy};
+use super::error::FactoryError;
+use super::pmx::channel_strip::PmxChannelLayout;
+use super::pmx::mod_host::plugins::PmxPlugin;
+use super::pmx::pipewire::{
+    node::PmxNode,
+    pipewire_client::PipewireClient,
+    port::{PmxPort, PmxPortDirection},
+    ListNodesRequest,
+};
+use super::transaction::PluginLink;
+
+/// Which of the input ports of a plugin a connection should use. Cross faders
+/// take the left source on the first half of their inputs and the right
+/// source on the second half.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputGroup {
+    All,
+    CrossFaderLeft,
+    CrossFaderRight,
+}
+
+/// How the ports of two plugins are wired: the number of channels the strip
+/// carries and which inputs of the receiving plugin are used.
+#[derive(Debug, Clone, Copy)]
+pub struct Wiring {
+    pub channels: usize,
+    pub input_group: InputGroup,
+}
+
+impl Wiring {
+    pub fn new(layout: PmxChannelLayout, input_group: InputGroup) -> Self {
+        Wiring {
+            channels: channel_count(layout),
+            input_group,
+        }
+    }
+}
+
+pub fn channel_count(layout: PmxChannelLayout) -> usize {
+    match layout {
+        PmxChannelLayout::Mono => 1,
+        PmxChannelLayout::Stereo => 2,
+        PmxChannelLayout::Surround51 => 6,
+    }
+}
+
+/// Works out the links needed to feed the outputs of one plugin into the
+/// inputs of another, based on the ports PipeWire reports for both nodes.
+/// A side the port mappings declare mono is fanned out to, or mixed down
+/// from, all channels of the other side.
+pub async fn plan_links(
+    output: &PmxPlugin,
+    input: &PmxPlugin,
+    wiring: Wiring,
+    config: &FactoryConfig,
+    pipewire_client: PipewireClient<Channel>,
+    logger: &Logger,
+) -> Result<Vec<PluginLink>, FactoryError> {
+    let nodes = list_nodes(config, pipewire_client, logger).await?;
+    links_between(&nodes, output, input, wiring, &config.port_mappings)
+}
+
+/// How many polls in a row the ports of both plugins have to stay the same
+/// before ports that are still missing count as never coming.
+const SETTLED_POLLS: usize = 3;
+
+/// Plans the links for freshly created plugins. PipeWire registers the node
+/// and ports of a new plugin a little after mod-host created it, one port at
+/// a time, so the nodes are polled until both plugins have every port the
+/// links need. Planning on a partly registered node would wire the channels
+/// to the wrong ports.
+///
+/// Once both nodes are there and their ports stop changing while some are
+/// still missing, the plugins cannot carry the layout, like a stereo plugin
+/// in a surround strip or a port mapping naming a port the plugin does not
+/// have. That is reported as `FailedPrecondition` right away instead of
+/// waiting for the timeout.
+pub async fn plan_links_when_ready(
+    output: &PmxPlugin,
+    input: &PmxPlugin,
+    wiring: Wiring,
+    config: &FactoryConfig,
+    pipewire_client: PipewireClient<Channel>,
+    logger: &Logger,
+) -> Result<Vec<PluginLink>, FactoryError> {
+    let timeout = Duration::from_millis(config.node_wait.timeout_ms);
+    let deadline = Instant::now() + timeout;
+    let mut last_ports = None;
+    let mut settled_polls = 0;
+    loop {
+        let nodes = list_nodes(config, pipewire_client.clone(), logger).await?;
+        let missing = match links_between(&nodes, output, input, wiring, &config.port_mappings) {
+            Ok(links) => return Ok(links),
+            Err(FactoryError::NotFound(missing)) => missing,
+            Err(error) => return Err(error),
+        };
+        let ports = registered_ports(&nodes, output, input);
+        settled_polls = match ports.is_some() && ports == last_ports {
+            true => settled_polls + 1,
+            false => 1,
+        };
+        last_ports = ports;
+        if settled_polls >= SETTLED_POLLS {
+            return Err(FactoryError::FailedPrecondition(format!(
+                "{} and {} cannot carry {} channels: missing {missing}",
+                output.name, input.name, wiring.channels
+            )));
+        }
+        if Instant::now() >= deadline {
+            return Err(FactoryError::Pipewire(Status::deadline_exceeded(format!(
+                "ports of {} and {} not ready after {timeout:?}: missing {missing}",
+                output.name, input.name
+            ))));
+        }
+        logger.log_info(&format!(
+            "Waiting for pipewire ports of {} and {}: missing {missing}",
+            output.name, input.name
+        ));
+        tokio::time::sleep(Duration::from_millis(config.node_wait.poll_interval_ms)).await;
+    }
+}
+
+/// The ports PipeWire registered so far for the nodes of both plugins, or
+/// `None` while one of the nodes is not there yet.
+fn registered_ports(
+    nodes: &[PmxNode],
+    output: &PmxPlugin,
+    input: &PmxPlugin,
+) -> Option<(Vec<(i32, u32)>, Vec<(i32, u32)>)> {
+    let ports = |plugin: &PmxPlugin| {
+        nodes
+            .iter()
+            .find(|node| node.name == plugin.name)
+            .map(|node| {
+                node.ports
+                    .iter()
+                    .map(|port| (port.direction, port.id))
+                    .collect::<Vec<_>>()
+            })
+    };
+    Some((ports(output)?, ports(input)?))
+}
+
+fn links_between(
+    nodes: &[PmxNode],
+    output: &PmxPlugin,
+    input: &PmxPlugin,
+    wiring: Wiring,
+    port_mappings: &[PortMappingConfig],
+) -> Result<Vec<PluginLink>, FactoryError> {
+    let groups = match wiring.input_group {
+        InputGroup::All => 1,
+        InputGroup::CrossFaderLeft | InputGroup::CrossFaderRight => 2,
+    };
+    let output_ports = carried_ports(
+        nodes,
+        output,
+        PmxPortDirection::Out,
+        wiring.channels,
+        1,
+        port_mappings,
+    )?;
+    let input_ports = carried_ports(
+        nodes,
+        input,
+        PmxPortDirection::In,
+        wiring.channels,
+        groups,
+        port_mappings,
+    )?;
+    let group = input_ports.len() / groups;
+    let input_ports = match wiring.input_group {
+        InputGroup::All => &input_ports[..],
+        InputGroup::CrossFaderLeft => &input_ports[..group],
+        InputGroup::CrossFaderRight => &input_ports[group..],
+    };
+
+    let pairs = if output_ports.len() == input_ports.len() {
+        match_ports(&output_ports, input_ports)
+    } else if output_ports.len() == 1 {
+        input_ports
+            .iter()
+            .map(|input_port| (&output_ports[0], input_port))
+            .collect()
+    } else {
+        output_ports
+            .iter()
+            .map(|output_port| (output_port, &input_ports[0]))
+            .collect()
+    };
+
+    Ok(pairs
+        .into_iter()
+        .map(|(output_port, input_port)| PluginLink {
+            output_node_name: output.name.clone(),
+            output_port_id: output_port.id,
+            input_node_name: input.name.clone(),
+            input_port_id: input_port.id,
+        })
+        .collect())
+}
+
+pub async fn list_nodes(
+    config: &FactoryConfig,
+    pipewire_client: PipewireClient<Channel>,
+    logger: &Logger,
+) -> Result<Vec<PmxNode>, FactoryError> {
+    let response = downstream::call(
+        CallPolicy::idempotent(&config.downstream.pipewire),
+        "pipewire list nodes",
+        logger,
+        || {
+            let mut client = pipewire_client.clone();
+            async move { client.list_nodes(ListNodesRequest {}).await }
+        },
+    )
+    .await
+    .map_err(FactoryError::Pipewire)?;
+    Ok(response.nodes)
+}
+
+/// The ports of a plugin in one direction that carry the channels of the
+/// strip, in the order they should be linked. `groups` is the number of
+/// sources sharing the inputs, two for a cross fader, whose first half takes
+/// the left and second half the right source.
+///
+/// A plugin needs a port for every channel of every group, surplus ports like
+/// sidechain inputs stay unconnected. A port mapping configured for the
+/// plugin selects and orders the ports by name. Only a mapping that names a
+/// single port per group makes the plugin mono on that side, its port is then
+/// fanned out to, or mixed down from, all channels.
+///
+/// Ports PipeWire has not registered yet are reported as `NotFound`.
+fn carried_ports(
+    nodes: &[PmxNode],
+    plugin: &PmxPlugin,
+    direction: PmxPortDirection,
+    channels: usize,
+    groups: usize,
+    port_mappings: &[PortMappingConfig],
+) -> Result<Vec<PmxPort>, FactoryError> {
+    let node = nodes
+        .iter()
+        .find(|node| node.name == plugin.name)
+        .ok_or_else(|| FactoryError::NotFound(format!("pipewire node {}", plugin.name)))?;
+    let mut ports: Vec<PmxPort> = node
+        .ports
+        .iter()
+        .filter(|port| port.direction == direction as i32)
+        .cloned()
+        .collect();
+    ports.sort_by_key(|port| port.id);
+    let wanted = channels * groups;
+    let kind = match direction {
+        PmxPortDirection::In => "input",
+        PmxPortDirection::Out => "output",
+    };
+
+    let names = port_mappings
+        .iter()
+        .find(|mapping| mapping.plugin_url == plugin.plugin_uri)
+        .map(|mapping| match direction {
+            PmxPortDirection::In => &mapping.inputs,
+            PmxPortDirection::Out => &mapping.outputs,
+        })
+        .filter(|names| !names.is_empty());
+    let Some(names) = names else {
+        if ports.len() < wanted {
+            return Err(FactoryError::NotFound(format!(
+                "{wanted} {kind} ports of {}, {} registered",
+                plugin.name,
+                ports.len()
+            )));
+        }
+        ports.truncate(wanted);
+        return Ok(ports);
+    };
+
+    if names.len() != groups && names.len() < wanted {
+        return Err(FactoryError::FailedPrecondition(format!(
+            "port mapping of {} names {} {kind} ports, expected {groups} for mono or {wanted}",
+            plugin.plugin_uri,
+            names.len()
+        )));
+    }
+    names
+        .iter()
+        .take(wanted)
+        .map(|name| {
+            ports
+                .iter()
+                .find(|port| &port.name == name)
+                .cloned()
+                .ok_or_else(|| {
+                    FactoryError::NotFound(format!("{kind} port {name} of {}", plugin.name))
+                })
+        })
+        .collect()
+}
+
+/// Pairs output and input ports by their channel when every output finds an
+/// input on the same channel, and by position otherwise.
+fn match_ports<'a>(
+    outputs: &'a [PmxPort],
+    inputs: &'a [PmxPort],
+) -> Vec<(&'a PmxPort, &'a PmxPort)> {
+    let has_channels = |ports: &[PmxPort]| ports.iter().all(|port| !port.channel.is_empty());
+    if has_channels(outputs) && has_channels(inputs) {
+        let by_channel: Vec<_> = outputs
+            .iter()
+            .filter_map(|output| {
+                inputs
+                    .iter()
+                    .find(|input| input.channel == output.channel)
+                    .map(|input| (output, input))
+            })
+            .collect();
+        if by_channel.len() == outputs.len() {
+            return by_channel;
+        }
+    }
+    outputs.iter().zip(inputs.iter()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(id: u32, direction: PmxPortDirection, name: &str, channel: &str) -> PmxPort {
+        PmxPort {
+            id,
+            name: String::from(name),
+            direction: direction as i32,
+            channel: String::from(channel),
+        }
+    }
+
+    /// A node with the given input and output ports, as (name, channel),
+    /// numbered from 0 per direction.
+    fn node(id: u32, name: &str, inputs: &[(&str, &str)], outputs: &[(&str, &str)]) -> PmxNode {
+        let ports = |direction, ports: &[(&str, &str)]| -> Vec<PmxPort> {
+            ports
+                .iter()
+                .enumerate()
+                .map(|(id, (name, channel))| port(id as u32, direction, name, channel))
+                .collect()
+        };
+        PmxNode {
+            id,
+            name: String::from(name),
+            ports: [
+                ports(PmxPortDirection::In, inputs),
+                ports(PmxPortDirection::Out, outputs),
+            ]
+            .concat(),
+        }
+    }
+
+    fn plugin(name: &str, uri: &str) -> PmxPlugin {
+        PmxPlugin {
+            name: String::from(name),
+            plugin_uri: String::from(uri),
+            ..Default::default()
+        }
+    }
+
+    fn mapping(uri: &str, inputs: &[&str], outputs: &[&str]) -> PortMappingConfig {
+        PortMappingConfig {
+            plugin_url: String::from(uri),
+            inputs: inputs.iter().map(|name| String::from(*name)).collect(),
+            outputs: outputs.iter().map(|name| String::from(*name)).collect(),
+        }
+    }
+
+    const STEREO: &[(&str, &str)] = &[("left", "FL"), ("right", "FR")];
+
+    fn port_pairs(links: &[PluginLink]) -> Vec<(u32, u32)> {
+        links
+            .iter()
+            .map(|link| (link.output_port_id, link.input_port_id))
+            .collect()
+    }
+
+    fn plan(
+        nodes: &[PmxNode],
+        layout: PmxChannelLayout,
+        input_group: InputGroup,
+        port_mappings: &[PortMappingConfig],
+    ) -> Result<Vec<PluginLink>, FactoryError> {
+        links_between(
+            nodes,
+            &plugin("source", "urn:source"),
+            &plugin("sink", "urn:sink"),
+            Wiring::new(layout, input_group),
+            port_mappings,
+        )
+    }
+
+    #[test]
+    fn stereo_ports_are_linked_by_channel() {
+        let nodes = [
+            node(10, "source", &[], STEREO),
+            node(11, "sink", &[("right", "FR"), ("left", "FL")], &[]),
+        ];
+        let links = plan(&nodes, PmxChannelLayout::Stereo, InputGroup::All, &[]).unwrap();
+        assert_eq!(port_pairs(&links), [(0, 1), (1, 0)]);
+        assert_eq!(links[0].output_node_name, "source");
+        assert_eq!(links[0].input_node_name, "sink");
+    }
+
+    #[test]
+    fn mono_output_mapped_as_mono_is_fanned_out() {
+        let nodes = [
+            node(10, "source", &[], &[("out", "MONO")]),
+            node(11, "sink", STEREO, &[]),
+        ];
+        let mappings = [mapping("urn:source", &[], &["out"])];
+        let links = plan(&nodes, PmxChannelLayout::Stereo, InputGroup::All, &mappings).unwrap();
+        assert_eq!(port_pairs(&links), [(0, 0), (0, 1)]);
+    }
+
+    #[test]
+    fn stereo_output_into_input_mapped_as_mono_is_mixed_down() {
+        let nodes = [
+            node(10, "source", &[], STEREO),
+            node(11, "sink", &[("in", "MONO")], &[]),
+        ];
+        let mappings = [mapping("urn:sink", &["in"], &[])];
+        let links = plan(&nodes, PmxChannelLayout::Stereo, InputGroup::All, &mappings).unwrap();
+        assert_eq!(port_pairs(&links), [(0, 0), (1, 0)]);
+    }
+
+    #[test]
+    fn single_port_without_mapping_is_not_taken_for_mono() {
+        let nodes = [
+            node(10, "source", &[], &[("left", "FL")]),
+            node(11, "sink", STEREO, &[]),
+        ];
+        let result = plan(&nodes, PmxChannelLayout::Stereo, InputGroup::All, &[]);
+        assert!(matches!(result, Err(FactoryError::NotFound(_))));
+    }
+
+    #[test]
+    fn surround_ports_are_linked_by_channel() {
+        let outputs = [
+            ("fl", "FL"),
+            ("fr", "FR"),
+            ("fc", "FC"),
+            ("lfe", "LFE"),
+            ("rl", "RL"),
+            ("rr", "RR"),
+        ];
+        let inputs = [
+            ("rr", "RR"),
+            ("rl", "RL"),
+            ("lfe", "LFE"),
+            ("fc", "FC"),
+            ("fr", "FR"),
+            ("fl", "FL"),
+        ];
+        let nodes = [
+            node(10, "source", &[], &outputs),
+            node(11, "sink", &inputs, &[]),
+        ];
+        let links = plan(&nodes, PmxChannelLayout::Surround51, InputGroup::All, &[]).unwrap();
+        assert_eq!(
+            port_pairs(&links),
+            [(0, 5), (1, 4), (2, 3), (3, 2), (4, 1), (5, 0)]
+        );
+    }
+
+    #[test]
+    fn mapped_port_names_select_and_order_ports() {
+        let nodes = [
+            node(
+                10,
+                "source",
+                &[],
+                &[("sidechain", ""), ("out_r", ""), ("out_l", "")],
+            ),
+            node(11, "sink", STEREO, &[]),
+        ];
+        let mappings = [mapping("urn:source", &[], &["out_l", "out_r"])];
+        let links = plan(&nodes, PmxChannelLayout::Stereo, InputGroup::All, &mappings).unwrap();
+        assert_eq!(port_pairs(&links), [(2, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn mapped_port_that_is_not_registered_yet_is_not_found() {
+        let nodes = [
+            node(10, "source", &[], &[("out_l", "")]),
+            node(11, "sink", STEREO, &[]),
+        ];
+        let mappings = [mapping("urn:source", &[], &["out_l", "out_r"])];
+        let result = plan(&nodes, PmxChannelLayout::Stereo, InputGroup::All, &mappings);
+        assert!(matches!(result, Err(FactoryError::NotFound(_))));
+    }
+
+    #[test]
+    fn mapping_with_too_few_ports_is_a_configuration_error() {
+        let nodes = [
+            node(10, "source", &[], STEREO),
+            node(11, "sink", &[("a", ""), ("b", ""), ("c", "")], &[]),
+        ];
+        let mappings = [mapping("urn:sink", &["a", "b", "c"], &[])];
+        let result = plan(
+            &nodes,
+            PmxChannelLayout::Stereo,
+            InputGroup::CrossFaderLeft,
+            &mappings,
+        );
+        assert!(matches!(result, Err(FactoryError::FailedPrecondition(_))));
+    }
+
+    #[test]
+    fn cross_fader_sides_use_their_half_of_the_inputs() {
+        let cross_fader_inputs = [
+            ("left_l", "FL"),
+            ("left_r", "FR"),
+            ("right_l", "FL"),
+            ("right_r", "FR"),
+        ];
+        let nodes = [
+            node(10, "source", &[], STEREO),
+            node(11, "sink", &cross_fader_inputs, &[]),
+        ];
+        let left = plan(
+            &nodes,
+            PmxChannelLayout::Stereo,
+            InputGroup::CrossFaderLeft,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(port_pairs(&left), [(0, 0), (1, 1)]);
+        let right = plan(
+            &nodes,
+            PmxChannelLayout::Stereo,
+            InputGroup::CrossFaderRight,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(port_pairs(&right), [(0, 2), (1, 3)]);
+    }
+
+    #[test]
+    fn partly_registered_cross_fader_is_not_ready() {
+        let nodes = [
+            node(10, "source", &[], STEREO),
+            node(11, "sink", &[("left_l", "FL"), ("left_r", "FR")], &[]),
+        ];
+        let result = plan(
+            &nodes,
+            PmxChannelLayout::Stereo,
+            InputGroup::CrossFaderRight,
+            &[],
+        );
+        assert!(matches!(result, Err(FactoryError::NotFound(_))));
+    }
+}
@@ -0,0 +1,472 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use fr_pmx_config_lib::ParameterConfig;
+use itertools::Itertools;
+use tokio_util::sync::CancellationToken;
+use tonic::Code;
+
+use super::channel_strip_factory::{channel_strip_output_plugin_id, parameter_configs};
+use super::downstream::{self, CallPolicy};
+use super::error::FactoryError;
+use super::journal::Journal;
+use super::locks::ObjectKey;
+use super::pmx::{
+    channel_strip::{PmxChannelLayout, PmxChannelStrip},
+    mod_host::plugins::PmxPlugin,
+    output_stage::PmxOutputStage,
+    UpdateChannelStripRequest, UpdateOutputStageRequest,
+};
+use super::ports::{plan_links, plan_links_when_ready, InputGroup, Wiring};
+use super::transaction::Transaction;
+use super::verification::missing_links;
+use super::{utils, FactoryWorker};
+
+/// A difference between a registered object and the live graph.
+#[derive(Debug, Clone)]
+pub struct Drift {
+    pub object: String,
+    pub description: String,
+    pub repaired: bool,
+}
+
+/// The state of reconciling one registered object. Everything recreated for
+/// the object is recorded in one transaction, which is only begun once the
+/// first repair creates something, so passes that find nothing to do leave
+/// the journal alone.
+struct ReconcilePass<'a> {
+    object: String,
+    repair: bool,
+    /// The plugins already taken by an object, shared by all passes of a run.
+    claimed: &'a mut HashSet<u32>,
+    journal: Arc<Journal>,
+    transaction: Option<Transaction>,
+    drifts: Vec<Drift>,
+}
+
+impl ReconcilePass<'_> {
+    fn transaction(&mut self) -> Result<&mut Transaction, FactoryError> {
+        let transaction = match self.transaction.take() {
+            Some(transaction) => transaction,
+            None => Transaction::begin(
+                self.journal.clone(),
+                &format!("reconcile {}", self.object),
+                CancellationToken::new(),
+            )?,
+        };
+        Ok(self.transaction.insert(transaction))
+    }
+
+    fn report(&mut self, description: String, repaired: bool) {
+        self.drifts.push(Drift {
+            object: self.object.clone(),
+            description,
+            repaired,
+        });
+    }
+}
+
+/// A plugin of a registered object that exists in mod-host, either all along
+/// or because it was recreated.
+struct LivePlugin {
+    plugin: PmxPlugin,
+    recreated: bool,
+}
+
+impl FactoryWorker {
+    /// Compares every registered channel strip and output stage with the
+    /// plugins in mod-host and the links in PipeWire. With `repair` set,
+    /// missing plugins and links are recreated and the registry is updated.
+    ///
+    /// A registered plugin counts as alive when mod-host has an instance with
+    /// its ID and URI that no other object claimed. After mod-host restarted
+    /// it hands out the old IDs again, for the plugins recreated here among
+    /// others, so this keeps one object from adopting another one's plugin.
+    ///
+    /// The listing only tells which objects there are. Every object is read
+    /// again once it is locked, as another reconciliation or a delete may
+    /// have changed or removed it in the meantime.
+    pub(super) async fn reconcile(&self, repair: bool) -> Result<Vec<Drift>, FactoryError> {
+        let _build = self.builds.read().await;
+        self.logger.log_info("Reconciling registered objects");
+        let channel_strips = self.list_channel_strips().await?;
+        let output_stages = self.list_output_stages().await?;
+
+        // Channel strips go first, so output stages are linked to the plugins
+        // their strips have after the repair.
+        let mut drifts = Vec::new();
+        let mut claimed = HashSet::new();
+        for channel_strip in channel_strips {
+            let object = format!("channel strip {}", channel_strip.id);
+            match self
+                .reconcile_channel_strip(channel_strip.id, repair, &mut claimed)
+                .await
+            {
+                Ok(found) => drifts.extend(found),
+                Err(error) => drifts.push(Drift {
+                    object,
+                    description: format!("could not be reconciled: {error}"),
+                    repaired: false,
+                }),
+            }
+        }
+        for output_stage in output_stages {
+            let object = format!("output stage {}", output_stage.id);
+            match self
+                .reconcile_output_stage(output_stage.id, repair, &mut claimed)
+                .await
+            {
+                Ok(found) => drifts.extend(found),
+                Err(error) => drifts.push(Drift {
+                    object,
+                    description: format!("could not be reconciled: {error}"),
+                    repaired: false,
+                }),
+            }
+        }
+        self.logger
+            .log_info(&format!("Reconciliation found {} drifts", drifts.len()));
+        Ok(drifts)
+    }
+
+    async fn reconcile_channel_strip(
+        &self,
+        id: u32,
+        repair: bool,
+        claimed: &mut HashSet<u32>,
+    ) -> Result<Vec<Drift>, FactoryError> {
+        let _lock = self.locks.lock(ObjectKey::ChannelStrip(id)).await;
+        let mut channel_strip = match self.get_channel_strip(id).await {
+            Ok(channel_strip) => channel_strip,
+            Err(error) if is_gone(&error) => return Ok(Vec::new()),
+            Err(error) => return Err(error),
+        };
+        let mut pass = self.begin_pass(
+            format!("channel strip {}", channel_strip.id),
+            repair,
+            claimed,
+        );
+        let result = self
+            .reconcile_channel_strip_graph(&mut pass, &mut channel_strip)
+            .await;
+        self.finish_pass(pass, result).await
+    }
+
+    async fn reconcile_channel_strip_graph(
+        &self,
+        pass: &mut ReconcilePass,
+        channel_strip: &mut PmxChannelStrip,
+    ) -> Result<(), FactoryError> {
+        let instances =
+            utils::list_plugins(&self.config, self.mod_host_client.clone(), &self.logger).await?;
+
+        let mut chain = Vec::new();
+        if let Some(id) = channel_strip.cross_fader_plugin_id {
+            let uri = self.config.channel_strip.cross_fader_plugin_url.clone();
+            let live = self
+                .reconcile_plugin(pass, &instances, id, uri, &[])
+                .await?;
+            if let Some(live) = &live {
+                channel_strip.cross_fader_plugin_id = Some(live.plugin.id);
+            }
+            chain.push(live);
+        }
+        for slot in channel_strip.slots.iter_mut() {
+            let parameters = parameter_configs(&slot.parameters);
+            let live = self
+                .reconcile_plugin(
+                    pass,
+                    &instances,
+                    slot.plugin_id,
+                    slot.plugin_uri.clone(),
+                    &parameters,
+                )
+                .await?;
+            if let Some(live) = &live {
+                slot.plugin_id = live.plugin.id;
+            }
+            chain.push(live);
+        }
+
+        let wiring = Wiring::new(channel_strip.channel_layout(), InputGroup::All);
+        for (output, input) in chain.iter().tuple_windows() {
+            if let (Some(output), Some(input)) = (output, input) {
+                self.reconcile_links(pass, output, input, wiring).await?;
+            }
+        }
+
+        if chain.iter().flatten().any(|live| live.recreated) {
+            let channel_strip = channel_strip.clone();
+            downstream::call(
+                CallPolicy::once(&self.config.downstream.registry),
+                "registry update channel strip",
+                &self.logger,
+                || {
+                    let mut client = self.registry_client.clone();
+                    let request = UpdateChannelStripRequest {
+                        channel_strip: Some(channel_strip.clone()),
+                    };
+                    async move { client.update_channel_strip(request).await }
+                },
+            )
+            .await
+            .map_err(FactoryError::Registry)?;
+        }
+        Ok(())
+    }
+
+    async fn reconcile_output_stage(
+        &self,
+        id: u32,
+        repair: bool,
+        claimed: &mut HashSet<u32>,
+    ) -> Result<Vec<Drift>, FactoryError> {
+        let _lock = self.locks.lock(ObjectKey::OutputStage(id)).await;
+        let mut output_stage = match self.get_output_stage(id).await {
+            Ok(output_stage) => output_stage,
+            Err(error) if is_gone(&error) => return Ok(Vec::new()),
+            Err(error) => return Err(error),
+        };
+        let mut pass =
+            self.begin_pass(format!("output stage {}", output_stage.id), repair, claimed);
+        let result = self
+            .reconcile_output_stage_graph(&mut pass, &mut output_stage)
+            .await;
+        self.finish_pass(pass, result).await
+    }
+
+    async fn reconcile_output_stage_graph(
+        &self,
+        pass: &mut ReconcilePass,
+        output_stage: &mut PmxOutputStage,
+    ) -> Result<(), FactoryError> {
+        // The strips are locked before they are read, so their plugins cannot
+        // change in between.
+        let _left_lock = self
+            .locks
+            .lock(ObjectKey::ChannelStrip(output_stage.left_channel_strip_id))
+            .await;
+        let _right_lock = self
+            .locks
+            .lock(ObjectKey::ChannelStrip(output_stage.right_channel_strip_id))
+            .await;
+        let left_channel_strip = self
+            .get_channel_strip(output_stage.left_channel_strip_id)
+            .await?;
+        let right_channel_strip = self
+            .get_channel_strip(output_stage.right_channel_strip_id)
+            .await?;
+        let instances =
+            utils::list_plugins(&self.config, self.mod_host_client.clone(), &self.logger).await?;
+
+        let uri = self.config.channel_strip.cross_fader_plugin_url.clone();
+        let Some(cross_fader) = self
+            .reconcile_plugin(
+                pass,
+                &instances,
+                output_stage.cross_fader_plugin_id,
+                uri,
+                &[],
+            )
+            .await?
+        else {
+            return Ok(());
+        };
+
+        let sides = [
+            (&left_channel_strip, InputGroup::CrossFaderLeft),
+            (&right_channel_strip, InputGroup::CrossFaderRight),
+        ];
+        for (channel_strip, input_group) in sides {
+            // A strip whose output plugin is gone was already reported, and
+            // repaired, while reconciling the strip itself.
+            let Some(output) = channel_strip_output_plugin_id(channel_strip)
+                .and_then(|id| utils::find_plugin(&instances, id))
+            else {
+                continue;
+            };
+            let output = LivePlugin {
+                plugin: output.clone(),
+                recreated: false,
+            };
+            let wiring = Wiring::new(PmxChannelLayout::Stereo, input_group);
+            self.reconcile_links(pass, &output, &cross_fader, wiring)
+                .await?;
+        }
+
+        if cross_fader.recreated {
+            output_stage.cross_fader_plugin_id = cross_fader.plugin.id;
+            let output_stage = output_stage.clone();
+            downstream::call(
+                CallPolicy::once(&self.config.downstream.registry),
+                "registry update output stage",
+                &self.logger,
+                || {
+                    let mut client = self.registry_client.clone();
+                    let request = UpdateOutputStageRequest {
+                        output_stage: Some(output_stage.clone()),
+                    };
+                    async move { client.update_output_stage(request).await }
+                },
+            )
+            .await
+            .map_err(FactoryError::Registry)?;
+        }
+        Ok(())
+    }
+
+    fn begin_pass<'a>(
+        &self,
+        object: String,
+        repair: bool,
+        claimed: &'a mut HashSet<u32>,
+    ) -> ReconcilePass<'a> {
+        ReconcilePass {
+            object,
+            repair,
+            claimed,
+            journal: self.journal.clone(),
+            transaction: None,
+            drifts: Vec::new(),
+        }
+    }
+
+    async fn finish_pass(
+        &self,
+        pass: ReconcilePass<'_>,
+        result: Result<(), FactoryError>,
+    ) -> Result<Vec<Drift>, FactoryError> {
+        let result = result.map(|_| pass.drifts);
+        match pass.transaction {
+            Some(transaction) => self.commit_or_roll_back(result, transaction).await,
+            None => result,
+        }
+    }
+
+    /// Looks up a plugin of a registered object in mod-host and recreates it
+    /// when it is missing and the pass repairs. The plugin is claimed for the
+    /// object either way.
+    async fn reconcile_plugin(
+        &self,
+        pass: &mut ReconcilePass,
+        instances: &[PmxPlugin],
+        id: u32,
+        uri: String,
+        parameters: &[ParameterConfig],
+    ) -> Result<Option<LivePlugin>, FactoryError> {
+        let live = utils::find_plugin(instances, id)
+            .filter(|plugin| plugin.plugin_uri == uri && !pass.claimed.contains(&plugin.id));
+        if let Some(plugin) = live {
+            pass.claimed.insert(plugin.id);
+            return Ok(Some(LivePlugin {
+                plugin: plugin.clone(),
+                recreated: false,
+            }));
+        }
+        if !pass.repair {
+            pass.report(format!("plugin {id} ({uri}) is missing"), false);
+            return Ok(None);
+        }
+
+        let plugin = utils::create_plugin(
+            uri.clone(),
+            &self.config,
+            self.mod_host_client.clone(),
+            pass.transaction()?,
+            &self.logger,
+        )
+        .await?;
+        pass.claimed.insert(plugin.id);
+        utils::set_parameters(
+            &plugin,
+            parameters,
+            &self.config,
+            self.mod_host_client.clone(),
+            &self.logger,
+        )
+        .await?;
+        pass.report(
+            format!("plugin {id} ({uri}) is missing, recreated as {}", plugin.id),
+            true,
+        );
+        Ok(Some(LivePlugin {
+            plugin,
+            recreated: true,
+        }))
+    }
+
+    /// Reports the links between two plugins that PipeWire does not have and
+    /// creates them when the pass repairs.
+    async fn reconcile_links(
+        &self,
+        pass: &mut ReconcilePass,
+        output: &LivePlugin,
+        input: &LivePlugin,
+        wiring: Wiring,
+    ) -> Result<(), FactoryError> {
+        let pipewire_client = self.pipewire_client.clone();
+        let expected = match output.recreated || input.recreated {
+            true => {
+                plan_links_when_ready(
+                    &output.plugin,
+                    &input.plugin,
+                    wiring,
+                    &self.config,
+                    pipewire_client.clone(),
+                    &self.logger,
+                )
+                .await?
+            }
+            false => {
+                plan_links(
+                    &output.plugin,
+                    &input.plugin,
+                    wiring,
+                    &self.config,
+                    pipewire_client.clone(),
+                    &self.logger,
+                )
+                .await?
+            }
+        };
+        let missing = missing_links(
+            &expected,
+            &self.config,
+            pipewire_client.clone(),
+            &self.logger,
+        )
+        .await?;
+
+        for link in missing {
+            let description = format!(
+                "link {}:{} -> {}:{} is missing",
+                link.output_node_name,
+                link.output_port_id,
+                link.input_node_name,
+                link.input_port_id
+            );
+            if pass.repair {
+                utils::create_link(
+                    link,
+                    &self.config,
+                    &pipewire_client,
+                    pass.transaction()?,
+                    &self.logger,
+                )
+                .await?;
+            }
+            pass.report(description, pass.repair);
+        }
+        Ok(())
+    }
+}
+
+/// Whether the registry no longer knows an object, because it was deleted
+/// after the objects were listed.
+fn is_gone(error: &FactoryError) -> bool {
+    match error {
+        FactoryError::NotFound(_) => true,
+        FactoryError::Registry(status) => status.code() == Code::NotFound,
+        _ => false,
+    }
+}
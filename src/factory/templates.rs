@@ -0,0 +1,214 @@
+use std::collections::HashSet;
+
+use fr_pmx_config_lib::{
+    ChannelStripTemplateConfig, FactoryConfig, ParameterConfig, PluginSlotConfig,
+};
+
+use super::error::FactoryError;
+
+/// A parameter value requested for one slot of a new channel strip.
+#[derive(Debug, Clone)]
+pub struct ParameterPreset {
+    pub slot: String,
+    pub symbol: String,
+    pub value: f32,
+}
+
+/// Resolves the plugin slots of a channel strip. Without a template name the
+/// default chain from the channel strip configuration is used.
+///
+/// An unknown template name is the caller's mistake and reported as
+/// `Validation`. A configured chain that cannot be built is the server's and
+/// reported as `FailedPrecondition`.
+pub fn resolve_slots(
+    config: &FactoryConfig,
+    template: Option<&str>,
+) -> Result<Vec<PluginSlotConfig>, FactoryError> {
+    select_slots(
+        &config.channel_strip.slots,
+        &config.channel_strip.templates,
+        template,
+    )
+}
+
+fn select_slots(
+    default_slots: &[PluginSlotConfig],
+    templates: &[ChannelStripTemplateConfig],
+    template: Option<&str>,
+) -> Result<Vec<PluginSlotConfig>, FactoryError> {
+    let slots = match template {
+        None => default_slots,
+        Some(name) => {
+            &templates
+                .iter()
+                .find(|template| template.name == name)
+                .ok_or_else(|| FactoryError::Validation(format!("unknown template '{name}'")))?
+                .slots
+        }
+    };
+    validate_slots(template.unwrap_or("default"), slots)?;
+    Ok(slots.to_vec())
+}
+
+/// Applies the presets of a request on top of the parameter values configured
+/// for the slots.
+pub fn apply_presets(
+    slots: &mut [PluginSlotConfig],
+    presets: Vec<ParameterPreset>,
+) -> Result<(), FactoryError> {
+    for preset in presets {
+        let slot = slots
+            .iter_mut()
+            .find(|slot| slot.name == preset.slot)
+            .ok_or_else(|| {
+                FactoryError::Validation(format!(
+                    "parameter preset for unknown slot '{}'",
+                    preset.slot
+                ))
+            })?;
+        match slot
+            .parameters
+            .iter_mut()
+            .find(|parameter| parameter.symbol == preset.symbol)
+        {
+            Some(parameter) => parameter.value = preset.value,
+            None => slot.parameters.push(ParameterConfig {
+                symbol: preset.symbol,
+                value: preset.value,
+            }),
+        }
+    }
+    Ok(())
+}
+
+fn validate_slots(template: &str, slots: &[PluginSlotConfig]) -> Result<(), FactoryError> {
+    if slots.is_empty() {
+        return Err(FactoryError::FailedPrecondition(format!(
+            "configured template '{template}' has no plugin slots"
+        )));
+    }
+
+    let mut names = HashSet::new();
+    for slot in slots {
+        if slot.name.is_empty() || slot.plugin_url.is_empty() {
+            return Err(FactoryError::FailedPrecondition(format!(
+                "configured template '{template}' has a slot without name or plugin url"
+            )));
+        }
+        if !names.insert(slot.name.as_str()) {
+            return Err(FactoryError::FailedPrecondition(format!(
+                "configured template '{template}' has more than one slot named '{}'",
+                slot.name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slot(name: &str, plugin_url: &str, parameters: &[(&str, f32)]) -> PluginSlotConfig {
+        PluginSlotConfig {
+            name: String::from(name),
+            plugin_url: String::from(plugin_url),
+            parameters: parameters
+                .iter()
+                .map(|(symbol, value)| ParameterConfig {
+                    symbol: String::from(*symbol),
+                    value: *value,
+                })
+                .collect(),
+        }
+    }
+
+    fn template(name: &str, slots: Vec<PluginSlotConfig>) -> ChannelStripTemplateConfig {
+        ChannelStripTemplateConfig {
+            name: String::from(name),
+            slots,
+        }
+    }
+
+    fn preset(slot: &str, symbol: &str, value: f32) -> ParameterPreset {
+        ParameterPreset {
+            slot: String::from(slot),
+            symbol: String::from(symbol),
+            value,
+        }
+    }
+
+    fn slot_names(slots: &[PluginSlotConfig]) -> Vec<&str> {
+        slots.iter().map(|slot| slot.name.as_str()).collect()
+    }
+
+    #[test]
+    fn default_chain_is_used_without_template() {
+        let default_slots = [slot("gain", "urn:gain", &[])];
+        let templates = [template("vocal", vec![slot("eq", "urn:eq", &[])])];
+        let slots = select_slots(&default_slots, &templates, None).unwrap();
+        assert_eq!(slot_names(&slots), ["gain"]);
+    }
+
+    #[test]
+    fn named_template_replaces_the_default_chain() {
+        let default_slots = [slot("gain", "urn:gain", &[])];
+        let templates = [template(
+            "vocal",
+            vec![
+                slot("eq", "urn:eq", &[]),
+                slot("compressor", "urn:comp", &[]),
+            ],
+        )];
+        let slots = select_slots(&default_slots, &templates, Some("vocal")).unwrap();
+        assert_eq!(slot_names(&slots), ["eq", "compressor"]);
+    }
+
+    #[test]
+    fn unknown_template_is_the_callers_mistake() {
+        let default_slots = [slot("gain", "urn:gain", &[])];
+        let result = select_slots(&default_slots, &[], Some("missing"));
+        assert!(matches!(result, Err(FactoryError::Validation(_))));
+    }
+
+    #[test]
+    fn broken_configured_chains_are_configuration_errors() {
+        let empty = select_slots(&[], &[], None);
+        assert!(matches!(empty, Err(FactoryError::FailedPrecondition(_))));
+
+        let templates = [template(
+            "doubled",
+            vec![slot("eq", "urn:eq", &[]), slot("eq", "urn:eq", &[])],
+        )];
+        let doubled = select_slots(&[], &templates, Some("doubled"));
+        assert!(matches!(doubled, Err(FactoryError::FailedPrecondition(_))));
+
+        let unnamed = select_slots(&[slot("", "urn:eq", &[])], &[], None);
+        assert!(matches!(unnamed, Err(FactoryError::FailedPrecondition(_))));
+    }
+
+    #[test]
+    fn presets_override_and_extend_configured_parameters() {
+        let mut slots = vec![
+            slot("gain", "urn:gain", &[("level", 0.0)]),
+            slot("eq", "urn:eq", &[]),
+        ];
+        apply_presets(
+            &mut slots,
+            vec![preset("gain", "level", -6.0), preset("eq", "low", 2.0)],
+        )
+        .unwrap();
+        assert_eq!(slots[0].parameters.len(), 1);
+        assert_eq!(slots[0].parameters[0].value, -6.0);
+        assert_eq!(slots[1].parameters[0].symbol, "low");
+        assert_eq!(slots[1].parameters[0].value, 2.0);
+    }
+
+    #[test]
+    fn preset_for_unknown_slot_is_the_callers_mistake() {
+        let mut slots = vec![slot("gain", "urn:gain", &[])];
+        let result = apply_presets(&mut slots, vec![preset("reverb", "mix", 0.5)]);
+        assert!(matches!(result, Err(FactoryError::Validation(_))));
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use fr_logging::Logger;
+use fr_pmx_config_lib::FactoryConfig;
+use tokio_util::sync::CancellationToken;
+use tonic::transport::Channel;
+use tonic::Code;
+
+use super::downstream::{self, CallPolicy};
+use super::error::FactoryError;
+use super::journal::{Journal, Operation};
+use super::pmx::{
+    mod_host::{
+        mod_host_proxy_client::ModHostProxyClient, plugins::PmxPlugin, RemovePluginInstanceRequest,
+    },
+    pipewire::{pipewire_client::PipewireClient, DeleteLinkByNameRequest},
+    pmx_registry_client::PmxRegistryClient,
+    UnregisterChannelStripRequest, UnregisterOutputStageRequest,
+};
+use super::utils;
+
+/// A link between two plugin ports, addressed by node name the way the
+/// pipewire registry's CreateLinkByName and DeleteLinkByName expect it.
+///
+/// Port IDs are the node-local `port.id` PipeWire gives every port, counted
+/// from 0 per direction: the outputs of a stereo plugin are 0 and 1, the
+/// right inputs of a cross fader 2 and 3. The pipewire registry reports the
+/// same IDs as `PmxPort.id` and as the port IDs of a `PmxLink`, whereas node
+/// IDs are global object IDs.
+#[derive(Debug, Clone)]
+pub struct PluginLink {
+    pub output_node_name: String,
+    pub output_port_id: u32,
+    pub input_node_name: String,
+    pub input_port_id: u32,
+}
+
+/// A plugin instance the way mod-host reported it when it was created.
+/// mod-host hands out the IDs of removed instances again, after a restart
+/// among others, so the name and URI tell the instance apart from a later
+/// one that got the same ID.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PluginInstance {
+    pub id: u32,
+    pub name: String,
+    pub plugin_uri: String,
+}
+
+impl PluginInstance {
+    fn is(&self, plugin: &PmxPlugin) -> bool {
+        self.id == plugin.id && self.name == plugin.name && self.plugin_uri == plugin.plugin_uri
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Resource {
+    Plugin(PluginInstance),
+    Link(PluginLink),
+    ChannelStripRegistration(u32),
+    OutputStageRegistration(u32),
+}
+
+/// Records every plugin, link and registry entry created while building a
+/// channel strip or output stage, so a failed build can be undone. The
+/// resources are also written to the journal, which keeps them around when
+/// the factory dies before the build is committed or rolled back. It also
+/// carries the cancellation of the request the build belongs to.
+#[derive(Debug)]
+pub struct Transaction {
+    resources: Vec<Resource>,
+    cancellation: CancellationToken,
+    journal: Arc<Journal>,
+    operation: u64,
+}
+
+impl Transaction {
+    pub fn begin(
+        journal: Arc<Journal>,
+        description: &str,
+        cancellation: CancellationToken,
+    ) -> Result<Self, FactoryError> {
+        let operation = journal.begin(description)?;
+        Ok(Transaction {
+            resources: Vec::new(),
+            cancellation,
+            journal,
+            operation,
+        })
+    }
+
+    /// Picks up an operation an earlier run of the factory left unfinished.
+    pub fn resume(journal: Arc<Journal>, operation: Operation) -> Self {
+        Transaction {
+            resources: operation.resources,
+            cancellation: CancellationToken::new(),
+            journal,
+            operation: operation.id,
+        }
+    }
+
+    /// A transaction for work that runs alongside this one and is merged back
+    /// afterwards. It belongs to the same operation and is cancelled together
+    /// with this transaction.
+    pub fn branch(&self) -> Self {
+        Transaction {
+            resources: Vec::new(),
+            cancellation: self.cancellation.clone(),
+            journal: self.journal.clone(),
+            operation: self.operation,
+        }
+    }
+
+    /// Called before every step that creates a resource, so a cancelled
+    /// request stops at a point where everything it created is recorded.
+    pub fn ensure_not_cancelled(&self) -> Result<(), FactoryError> {
+        match self.cancellation.is_cancelled() {
+            true => Err(FactoryError::Cancelled),
+            false => Ok(()),
+        }
+    }
+
+    pub fn record_plugin(&mut self, plugin: &PmxPlugin) -> Result<(), FactoryError> {
+        self.record(Resource::Plugin(PluginInstance {
+            id: plugin.id,
+            name: plugin.name.clone(),
+            plugin_uri: plugin.plugin_uri.clone(),
+        }))
+    }
+
+    pub fn record_link(&mut self, link: PluginLink) -> Result<(), FactoryError> {
+        self.record(Resource::Link(link))
+    }
+
+    pub fn record_channel_strip_registration(&mut self, id: u32) -> Result<(), FactoryError> {
+        self.record(Resource::ChannelStripRegistration(id))
+    }
+
+    pub fn record_output_stage_registration(&mut self, id: u32) -> Result<(), FactoryError> {
+        self.record(Resource::OutputStageRegistration(id))
+    }
+
+    // The resource is kept even when it cannot be journaled, so a rollback
+    // still removes it.
+    fn record(&mut self, resource: Resource) -> Result<(), FactoryError> {
+        self.resources.push(resource.clone());
+        self.journal.record(self.operation, &resource)
+    }
+
+    /// The links recorded so far, in the order they were created.
+    pub fn links(&self) -> Vec<PluginLink> {
+        self.resources
+            .iter()
+            .filter_map(|resource| match resource {
+                Resource::Link(link) => Some(link.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Takes over the resources of a transaction that ran alongside this one.
+    pub fn merge(&mut self, other: Transaction) {
+        self.resources.extend(other.resources);
+    }
+
+    /// Marks the operation as done, its resources stay in place.
+    pub fn commit(&self) -> Result<(), FactoryError> {
+        self.journal.end(self.operation)
+    }
+
+    /// Removes the recorded resources in reverse order of creation. Failures
+    /// are logged and do not stop the remaining resources from being removed.
+    /// An operation that could not be undone completely stays in the journal
+    /// and is handed back with the resources that are left, so the rollback
+    /// can be retried.
+    ///
+    /// A recorded plugin is only removed while mod-host still runs the same
+    /// instance under its ID. One that is gone or was replaced by another
+    /// instance with the same ID counts as removed, and so do the links to
+    /// its node, whose name may belong to the new instance by now.
+    pub async fn rollback(
+        self,
+        mod_host_client: ModHostProxyClient<Channel>,
+        pipewire_client: PipewireClient<Channel>,
+        registry_client: PmxRegistryClient<Channel>,
+        config: &FactoryConfig,
+        logger: &Logger,
+    ) -> Result<(), Transaction> {
+        logger.log_info("Rolling back partially created resources");
+        let recorded: Vec<&PluginInstance> = self
+            .resources
+            .iter()
+            .filter_map(|resource| match resource {
+                Resource::Plugin(instance) => Some(instance),
+                _ => None,
+            })
+            .collect();
+        let live = match recorded.is_empty() {
+            true => Vec::new(),
+            false => match utils::list_plugins(config, mod_host_client.clone(), logger).await {
+                Ok(live) => live,
+                Err(error) => {
+                    logger.log_error(&format!(
+                        "Failed to look up the plugins of operation {}: {error}",
+                        self.operation
+                    ));
+                    return Err(self);
+                }
+            },
+        };
+        let is_live = |instance: &PluginInstance| live.iter().any(|plugin| instance.is(plugin));
+        let replaced_nodes: HashSet<String> = recorded
+            .iter()
+            .filter(|instance| !is_live(instance))
+            .map(|instance| instance.name.clone())
+            .collect();
+
+        let mut remaining = Vec::new();
+        for resource in self.resources.into_iter().rev() {
+            let (description, result) = match resource.clone() {
+                Resource::ChannelStripRegistration(id) => {
+                    let result = downstream::call(
+                        CallPolicy::once(&config.downstream.registry),
+                        "registry unregister channel strip",
+                        logger,
+                        || {
+                            let mut client = registry_client.clone();
+                            async move {
+                                client
+                                    .unregister_channel_strip(UnregisterChannelStripRequest { id })
+                                    .await
+                            }
+                        },
+                    )
+                    .await;
+                    (format!("unregister channel strip {id}"), result)
+                }
+                Resource::OutputStageRegistration(id) => {
+                    let result = downstream::call(
+                        CallPolicy::once(&config.downstream.registry),
+                        "registry unregister output stage",
+                        logger,
+                        || {
+                            let mut client = registry_client.clone();
+                            async move {
+                                client
+                                    .unregister_output_stage(UnregisterOutputStageRequest { id })
+                                    .await
+                            }
+                        },
+                    )
+                    .await;
+                    (format!("unregister output stage {id}"), result)
+                }
+                Resource::Link(link)
+                    if replaced_nodes.contains(&link.output_node_name)
+                        || replaced_nodes.contains(&link.input_node_name) =>
+                {
+                    continue;
+                }
+                Resource::Link(link) => {
+                    let request = DeleteLinkByNameRequest {
+                        output_port_id: link.output_port_id,
+                        input_port_id: link.input_port_id,
+                        output_node_name: link.output_node_name.clone(),
+                        input_node_name: link.input_node_name.clone(),
+                    };
+                    let result = downstream::call(
+                        CallPolicy::idempotent(&config.downstream.pipewire),
+                        "pipewire delete link by name",
+                        logger,
+                        || {
+                            let mut client = pipewire_client.clone();
+                            let request = request.clone();
+                            async move { client.delete_link_by_name(request).await }
+                        },
+                    )
+                    .await;
+                    let description = format!(
+                        "remove link {}:{} -> {}:{}",
+                        link.output_node_name,
+                        link.output_port_id,
+                        link.input_node_name,
+                        link.input_port_id
+                    );
+                    (description, result)
+                }
+                Resource::Plugin(instance) if !is_live(&instance) => {
+                    logger.log_info(&format!(
+                        "Plugin {} is gone or was replaced, leaving it",
+                        instance.id
+                    ));
+                    continue;
+                }
+                Resource::Plugin(PluginInstance { id, .. }) => {
+                    let result = downstream::call(
+                        CallPolicy::once(&config.downstream.mod_host),
+                        "mod host remove plugin instance",
+                        logger,
+                        || {
+                            let mut client = mod_host_client.clone();
+                            async move {
+                                client
+                                    .remove_plugin_instance(RemovePluginInstanceRequest { id })
+                                    .await
+                            }
+                        },
+                    )
+                    .await;
+                    (format!("remove plugin {id}"), result)
+                }
+            };
+            // A resource that is already gone, for example because an earlier
+            // rollback got that far, counts as removed.
+            match result {
+                Err(status) if status.code() != Code::NotFound => {
+                    logger.log_error(&format!("Failed to {description}: {}", status.message()));
+                    remaining.push(resource);
+                }
+                _ => {}
+            }
+        }
+
+        if !remaining.is_empty() {
+            logger.log_error(&format!(
+                "Operation {} was not rolled back completely",
+                self.operation
+            ));
+            remaining.reverse();
+            return Err(Transaction {
+                resources: remaining,
+                ..self
+            });
+        }
+        if let Err(error) = self.journal.end(self.operation) {
+            logger.log_error(&format!(
+                "Failed to mark operation {} as rolled back: {error}",
+                self.operation
+            ));
+        }
+        Ok(())
+    }
+}
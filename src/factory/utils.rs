@@ -1,80 +1,378 @@
 use fr_logging::Logger;
+use fr_pmx_config_lib::{FactoryConfig, ParameterConfig};
 use tonic::transport::Channel;
-use tonic::Request;
 
-use super::pmx::mod_host::CreatePluginInstanceRequest;
+use super::downstream::{self, CallPolicy};
+use super::error::FactoryError;
+use super::pmx::channel_strip::PmxChannelLayout;
 use super::pmx::mod_host::{
     mod_host_proxy_client::ModHostProxyClient,
     plugins::{PmxPlugin, PmxPluginType},
 };
+use super::pmx::mod_host::{
+    CreatePluginInstanceRequest, ListPluginInstancesRequest, RemovePluginInstanceRequest,
+    SetPluginParameterRequest,
+};
 use super::pmx::pipewire::pipewire_client::PipewireClient;
-use super::pmx::pipewire::CreateLinkByNameRequest;
+use super::pmx::pipewire::{CreateLinkByNameRequest, DeleteLinkByNameRequest};
+use super::ports::{plan_links, plan_links_when_ready, InputGroup, Wiring};
+use super::transaction::{PluginLink, Transaction};
 
 pub async fn create_plugin(
     uri: String,
-    mut client: ModHostProxyClient<Channel>,
+    config: &FactoryConfig,
+    client: ModHostProxyClient<Channel>,
+    transaction: &mut Transaction,
     logger: &Logger,
-) -> PmxPlugin {
+) -> Result<PmxPlugin, FactoryError> {
+    transaction.ensure_not_cancelled()?;
     logger.log_info("Creating plugin");
     let request = CreatePluginInstanceRequest {
         plugin_type: PmxPluginType::Lv2 as i32,
-        plugin_uri: uri,
+        plugin_uri: uri.clone(),
     };
-    let response = client.create_plugin_instance(request).await.unwrap();
-    response.into_inner().plugin.unwrap()
+    let response = downstream::call(
+        CallPolicy::once(&config.downstream.mod_host),
+        "mod host create plugin instance",
+        logger,
+        || {
+            let mut client = client.clone();
+            let request = request.clone();
+            async move { client.create_plugin_instance(request).await }
+        },
+    )
+    .await
+    .map_err(FactoryError::ModHost)?;
+    let plugin = response
+        .plugin
+        .ok_or_else(|| FactoryError::Internal(format!("mod host returned no plugin for {uri}")))?;
+    transaction.record_plugin(&plugin)?;
+    Ok(plugin)
+}
+
+pub async fn set_parameters(
+    plugin: &PmxPlugin,
+    parameters: &[ParameterConfig],
+    config: &FactoryConfig,
+    client: ModHostProxyClient<Channel>,
+    logger: &Logger,
+) -> Result<(), FactoryError> {
+    for parameter in parameters {
+        logger.log_info(&format!(
+            "Setting parameter {} of plugin {} to {}",
+            parameter.symbol, plugin.id, parameter.value
+        ));
+        let request = SetPluginParameterRequest {
+            plugin_id: plugin.id,
+            symbol: parameter.symbol.clone(),
+            value: parameter.value,
+        };
+        downstream::call(
+            CallPolicy::idempotent(&config.downstream.mod_host),
+            "mod host set plugin parameter",
+            logger,
+            || {
+                let mut client = client.clone();
+                let request = request.clone();
+                async move { client.set_plugin_parameter(request).await }
+            },
+        )
+        .await
+        .map_err(FactoryError::ModHost)?;
+    }
+    Ok(())
+}
+
+pub async fn remove_plugin(
+    plugin: &PmxPlugin,
+    config: &FactoryConfig,
+    client: ModHostProxyClient<Channel>,
+    logger: &Logger,
+) -> Result<(), FactoryError> {
+    logger.log_info(&format!("Removing plugin {}", plugin.id));
+    downstream::call(
+        CallPolicy::once(&config.downstream.mod_host),
+        "mod host remove plugin instance",
+        logger,
+        || {
+            let mut client = client.clone();
+            async move {
+                client
+                    .remove_plugin_instance(RemovePluginInstanceRequest { id: plugin.id })
+                    .await
+            }
+        },
+    )
+    .await
+    .map_err(FactoryError::ModHost)?;
+    Ok(())
+}
+
+pub fn find_plugin(plugins: &[PmxPlugin], id: u32) -> Option<&PmxPlugin> {
+    plugins.iter().find(|plugin| plugin.id == id)
+}
+
+pub async fn list_plugins(
+    config: &FactoryConfig,
+    client: ModHostProxyClient<Channel>,
+    logger: &Logger,
+) -> Result<Vec<PmxPlugin>, FactoryError> {
+    let response = downstream::call(
+        CallPolicy::idempotent(&config.downstream.mod_host),
+        "mod host list plugin instances",
+        logger,
+        || {
+            let mut client = client.clone();
+            async move {
+                client
+                    .list_plugin_instances(ListPluginInstancesRequest {})
+                    .await
+            }
+        },
+    )
+    .await
+    .map_err(FactoryError::ModHost)?;
+    Ok(response.plugins)
 }
 
 pub async fn connect_cross_fader_left(
     output: &PmxPlugin,
     cross_fader: &PmxPlugin,
+    config: &FactoryConfig,
     pipewire_client: PipewireClient<Channel>,
+    transaction: &mut Transaction,
     logger: &Logger,
-) {
-    connect_plugins(output, cross_fader, pipewire_client, logger).await;
+) -> Result<(), FactoryError> {
+    connect(
+        output,
+        cross_fader,
+        Wiring::new(PmxChannelLayout::Stereo, InputGroup::CrossFaderLeft),
+        config,
+        pipewire_client,
+        transaction,
+        logger,
+    )
+    .await
 }
 
 pub async fn connect_cross_fader_right(
     output: &PmxPlugin,
     cross_fader: &PmxPlugin,
-    mut pipewire_client: PipewireClient<Channel>,
+    config: &FactoryConfig,
+    pipewire_client: PipewireClient<Channel>,
+    transaction: &mut Transaction,
     logger: &Logger,
-) {
-    logger.log_info("Connecting plugins");
-    let request = Request::new(CreateLinkByNameRequest {
-        output_port_id: 0,
-        input_port_id: 2,
-        output_node_name: output.name.clone(),
-        input_node_name: cross_fader.name.clone(),
-    });
-    pipewire_client.create_link_by_name(request).await.unwrap();
-    let request = Request::new(CreateLinkByNameRequest {
-        output_port_id: 1,
-        input_port_id: 3,
-        output_node_name: output.name.clone(),
-        input_node_name: cross_fader.name.clone(),
-    });
-    pipewire_client.create_link_by_name(request).await.unwrap();
+) -> Result<(), FactoryError> {
+    connect(
+        output,
+        cross_fader,
+        Wiring::new(PmxChannelLayout::Stereo, InputGroup::CrossFaderRight),
+        config,
+        pipewire_client,
+        transaction,
+        logger,
+    )
+    .await
 }
 
 pub async fn connect_plugins(
     output: &PmxPlugin,
     input: &PmxPlugin,
-    mut pipewire_client: PipewireClient<Channel>,
+    layout: PmxChannelLayout,
+    config: &FactoryConfig,
+    pipewire_client: PipewireClient<Channel>,
+    transaction: &mut Transaction,
     logger: &Logger,
-) {
+) -> Result<(), FactoryError> {
+    connect(
+        output,
+        input,
+        Wiring::new(layout, InputGroup::All),
+        config,
+        pipewire_client,
+        transaction,
+        logger,
+    )
+    .await
+}
+
+async fn connect(
+    output: &PmxPlugin,
+    input: &PmxPlugin,
+    wiring: Wiring,
+    config: &FactoryConfig,
+    pipewire_client: PipewireClient<Channel>,
+    transaction: &mut Transaction,
+    logger: &Logger,
+) -> Result<(), FactoryError> {
     logger.log_info("Connecting plugins");
-    let request = Request::new(CreateLinkByNameRequest {
-        output_port_id: 0,
-        input_port_id: 0,
-        output_node_name: output.name.clone(),
-        input_node_name: input.name.clone(),
-    });
-    pipewire_client.create_link_by_name(request).await.unwrap();
-    let request = Request::new(CreateLinkByNameRequest {
-        output_port_id: 1,
-        input_port_id: 1,
-        output_node_name: output.name.clone(),
-        input_node_name: input.name.clone(),
-    });
-    pipewire_client.create_link_by_name(request).await.unwrap();
+    let links = plan_links_when_ready(
+        output,
+        input,
+        wiring,
+        config,
+        pipewire_client.clone(),
+        logger,
+    )
+    .await?;
+    for link in links {
+        create_link(link, config, &pipewire_client, transaction, logger).await?;
+    }
+    Ok(())
+}
+
+pub async fn create_link(
+    link: PluginLink,
+    config: &FactoryConfig,
+    pipewire_client: &PipewireClient<Channel>,
+    transaction: &mut Transaction,
+    logger: &Logger,
+) -> Result<(), FactoryError> {
+    transaction.ensure_not_cancelled()?;
+    let request = CreateLinkByNameRequest {
+        output_port_id: link.output_port_id,
+        input_port_id: link.input_port_id,
+        output_node_name: link.output_node_name.clone(),
+        input_node_name: link.input_node_name.clone(),
+    };
+    downstream::call(
+        CallPolicy::idempotent(&config.downstream.pipewire),
+        "pipewire create link by name",
+        logger,
+        || {
+            let mut client = pipewire_client.clone();
+            let request = request.clone();
+            async move { client.create_link_by_name(request).await }
+        },
+    )
+    .await
+    .map_err(FactoryError::Pipewire)?;
+    transaction.record_link(link)?;
+    Ok(())
+}
+
+pub async fn disconnect_cross_fader_left(
+    output: &PmxPlugin,
+    cross_fader: &PmxPlugin,
+    config: &FactoryConfig,
+    pipewire_client: PipewireClient<Channel>,
+    logger: &Logger,
+) {
+    disconnect(
+        output,
+        cross_fader,
+        Wiring::new(PmxChannelLayout::Stereo, InputGroup::CrossFaderLeft),
+        config,
+        pipewire_client,
+        logger,
+    )
+    .await;
+}
+
+pub async fn disconnect_cross_fader_right(
+    output: &PmxPlugin,
+    cross_fader: &PmxPlugin,
+    config: &FactoryConfig,
+    pipewire_client: PipewireClient<Channel>,
+    logger: &Logger,
+) {
+    disconnect(
+        output,
+        cross_fader,
+        Wiring::new(PmxChannelLayout::Stereo, InputGroup::CrossFaderRight),
+        config,
+        pipewire_client,
+        logger,
+    )
+    .await;
+}
+
+pub async fn disconnect_plugins(
+    output: &PmxPlugin,
+    input: &PmxPlugin,
+    layout: PmxChannelLayout,
+    config: &FactoryConfig,
+    pipewire_client: PipewireClient<Channel>,
+    logger: &Logger,
+) {
+    disconnect(
+        output,
+        input,
+        Wiring::new(layout, InputGroup::All),
+        config,
+        pipewire_client,
+        logger,
+    )
+    .await;
+}
+
+// A link that cannot be removed is only logged. It disappears together with
+// its plugin anyway, and a missing link must not block the teardown.
+async fn disconnect(
+    output: &PmxPlugin,
+    input: &PmxPlugin,
+    wiring: Wiring,
+    config: &FactoryConfig,
+    pipewire_client: PipewireClient<Channel>,
+    logger: &Logger,
+) {
+    logger.log_info("Disconnecting plugins");
+    let links = plan_links(
+        output,
+        input,
+        wiring,
+        config,
+        pipewire_client.clone(),
+        logger,
+    )
+    .await;
+    let links = match links {
+        Ok(links) => links,
+        Err(error) => {
+            logger.log_error(&format!(
+                "Failed to look up links from {} to {}: {error}",
+                output.name, input.name
+            ));
+            return;
+        }
+    };
+    for link in links {
+        remove_link(&link, config, &pipewire_client, logger).await;
+    }
+}
+
+/// Removes a link, failures are only logged.
+pub async fn remove_link(
+    link: &PluginLink,
+    config: &FactoryConfig,
+    pipewire_client: &PipewireClient<Channel>,
+    logger: &Logger,
+) {
+    let request = DeleteLinkByNameRequest {
+        output_port_id: link.output_port_id,
+        input_port_id: link.input_port_id,
+        output_node_name: link.output_node_name.clone(),
+        input_node_name: link.input_node_name.clone(),
+    };
+    let result = downstream::call(
+        CallPolicy::idempotent(&config.downstream.pipewire),
+        "pipewire delete link by name",
+        logger,
+        || {
+            let mut client = pipewire_client.clone();
+            let request = request.clone();
+            async move { client.delete_link_by_name(request).await }
+        },
+    )
+    .await;
+    if let Err(status) = result {
+        logger.log_error(&format!(
+            "Failed to remove link {}:{} -> {}:{}: {}",
+            link.output_node_name,
+            link.output_port_id,
+            link.input_node_name,
+            link.input_port_id,
+            status.message()
+        ));
+    }
 }
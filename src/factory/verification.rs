@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use fr_logging::Logger;
+use fr_pmx_config_lib::FactoryConfig;
+use tokio::time::Instant;
+use tonic::transport::Channel;
+
+use super::downstream::{self, CallPolicy};
+use super::error::FactoryError;
+use super::pmx::pipewire::{link::PmxLink, pipewire_client::PipewireClient, ListLinksRequest};
+use super::ports::list_nodes;
+use super::transaction::{PluginLink, Transaction};
+use super::utils::create_link;
+
+/// A link a build created that PipeWire does not report.
+#[derive(Debug, Clone)]
+pub struct LinkDiscrepancy {
+    pub link: PluginLink,
+    pub repaired: bool,
+}
+
+/// Checks that every link recorded in the transaction exists in PipeWire.
+/// With `repair` set, missing links are created again.
+///
+/// PipeWire reports new links a little after they were created, like it does
+/// with nodes and ports, so the links are polled until they all show up or
+/// the node wait timeout passes. Only the links missing by then are reported.
+pub async fn verify_links(
+    transaction: &mut Transaction,
+    repair: bool,
+    config: &FactoryConfig,
+    pipewire_client: PipewireClient<Channel>,
+    logger: &Logger,
+) -> Result<Vec<LinkDiscrepancy>, FactoryError> {
+    logger.log_info("Verifying links");
+    let expected = transaction.links();
+    let deadline = Instant::now() + Duration::from_millis(config.node_wait.timeout_ms);
+    let missing = loop {
+        let missing = missing_links(&expected, config, pipewire_client.clone(), logger).await?;
+        if missing.is_empty() || Instant::now() >= deadline {
+            break missing;
+        }
+        logger.log_info(&format!(
+            "Waiting for pipewire to report {} links",
+            missing.len()
+        ));
+        tokio::time::sleep(Duration::from_millis(config.node_wait.poll_interval_ms)).await;
+    };
+
+    let mut discrepancies = Vec::with_capacity(missing.len());
+    for link in missing {
+        logger.log_error(&format!(
+            "Link {}:{} -> {}:{} is missing",
+            link.output_node_name, link.output_port_id, link.input_node_name, link.input_port_id
+        ));
+        let repaired = repair
+            && match create_link(link.clone(), config, &pipewire_client, transaction, logger).await
+            {
+                Ok(()) => true,
+                Err(error) => {
+                    logger.log_error(&format!("Failed to repair link: {error}"));
+                    false
+                }
+            };
+        discrepancies.push(LinkDiscrepancy { link, repaired });
+    }
+    Ok(discrepancies)
+}
+
+/// The links of `expected` that PipeWire does not report.
+pub async fn missing_links(
+    expected: &[PluginLink],
+    config: &FactoryConfig,
+    pipewire_client: PipewireClient<Channel>,
+    logger: &Logger,
+) -> Result<Vec<PluginLink>, FactoryError> {
+    let nodes = list_nodes(config, pipewire_client.clone(), logger).await?;
+    let links = list_links(config, pipewire_client, logger).await?;
+    let node_id = |name: &str| {
+        nodes
+            .iter()
+            .find(|node| node.name == name)
+            .map(|node| node.id)
+    };
+    let exists = |link: &PluginLink| {
+        let (Some(output_node_id), Some(input_node_id)) = (
+            node_id(&link.output_node_name),
+            node_id(&link.input_node_name),
+        ) else {
+            return false;
+        };
+        // Nodes are matched by their global ID and ports by their node-local
+        // ID, see PluginLink.
+        links.iter().any(|existing| {
+            existing.output_node_id == output_node_id
+                && existing.output_port_id == link.output_port_id
+                && existing.input_node_id == input_node_id
+                && existing.input_port_id == link.input_port_id
+        })
+    };
+    Ok(expected
+        .iter()
+        .filter(|link| !exists(link))
+        .cloned()
+        .collect())
+}
+
+pub async fn list_links(
+    config: &FactoryConfig,
+    pipewire_client: PipewireClient<Channel>,
+    logger: &Logger,
+) -> Result<Vec<PmxLink>, FactoryError> {
+    let response = downstream::call(
+        CallPolicy::idempotent(&config.downstream.pipewire),
+        "pipewire list links",
+        logger,
+        || {
+            let mut client = pipewire_client.clone();
+            async move { client.list_links(ListLinksRequest {}).await }
+        },
+    )
+    .await
+    .map_err(FactoryError::Pipewire)?;
+    Ok(response.links)
+}
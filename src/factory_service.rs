@@ -1,20 +1,35 @@
 use std::result::Result;
 
 use fr_logging::Logger;
-use pmx::factory::channel_strip::PmxChannelStrip;
+use fr_pmx_config_lib::ParameterConfig;
+use pmx::factory::channel_strip::{
+    PmxChannelStrip, PmxChannelStripSlot, PmxChannelStripTemplate, PmxChannelStripTemplateSlot,
+    PmxLinkDiscrepancy, PmxParameterValue,
+};
 use pmx::factory::output_stage::PmxOutputStage;
 use pmx::factory::pmx_factory_server::{PmxFactory, PmxFactoryServer};
-use pmx::factory::{CreateChannelStripRequest, CreateOutputStageRequest};
+use pmx::factory::{
+    CollectGarbageRequest, CollectGarbageResponse, CreateChannelStripRequest,
+    CreateOutputStageRequest, DeleteChannelStripRequest, DeleteChannelStripResponse,
+    DeleteOutputStageRequest, DeleteOutputStageResponse, GetQueueStatusRequest,
+    GetQueueStatusResponse, ListTemplatesRequest, ListTemplatesResponse, PmxDrift,
+    PmxOrphanedPlugin, ReconcileRequest, ReconcileResponse, RestoreFromRegistryRequest,
+    RestoreFromRegistryResponse,
+};
+use tokio::sync::mpsc::error::TrySendError;
 
-use tonic::{Request, Response, Status};
+use tonic::{Code, Request, Response, Status};
 
-use crate::factory::pmx::channel_strip::PmxChannelStripType;
-use crate::factory::FactoryRequest;
+use crate::factory::pmx::channel_strip::{PmxChannelLayout, PmxChannelStripType};
+use crate::factory::{Drift, FactoryError, FactoryRequest, LinkDiscrepancy, ParameterPreset};
 
 pub mod pmx {
     pub mod factory {
         tonic::include_proto!("pmx.factory");
 
+        pub const FILE_DESCRIPTOR_SET: &[u8] =
+            tonic::include_file_descriptor_set!("factory_descriptor");
+
         pub mod channel_strip {
             tonic::include_proto!("pmx.factory.channel_strip");
         }
@@ -26,21 +41,105 @@ pub mod pmx {
 }
 
 pub struct FactoryService {
-    sender: tokio::sync::mpsc::UnboundedSender<FactoryRequest>,
+    sender: tokio::sync::mpsc::Sender<FactoryRequest>,
     logger: Logger,
 }
 
 impl FactoryService {
-    pub fn new(sender: tokio::sync::mpsc::UnboundedSender<FactoryRequest>, logger: Logger) -> Self {
+    pub fn new(sender: tokio::sync::mpsc::Sender<FactoryRequest>, logger: Logger) -> Self {
         FactoryService { sender, logger }
     }
 
     pub fn new_server(
-        sender: tokio::sync::mpsc::UnboundedSender<FactoryRequest>,
+        sender: tokio::sync::mpsc::Sender<FactoryRequest>,
         logger: Logger,
     ) -> PmxFactoryServer<FactoryService> {
         PmxFactoryServer::new(FactoryService::new(sender, logger))
     }
+
+    /// Queues the request for the factory. Requests are rejected instead of
+    /// buffered when the queue is full, so clients can back off.
+    fn send_request(&self, request: FactoryRequest) -> Result<(), Status> {
+        self.sender.try_send(request).map_err(|error| match error {
+            TrySendError::Full(_) => {
+                self.logger
+                    .log_error("Request queue is full, rejecting request");
+                Status::resource_exhausted("factory request queue is full")
+            }
+            TrySendError::Closed(_) => Status::unavailable("factory is not running"),
+        })
+    }
+
+    /// The number of requests waiting for the factory.
+    fn queued_requests(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+}
+
+impl From<FactoryError> for Status {
+    fn from(error: FactoryError) -> Self {
+        let message = error.to_string();
+        match error {
+            FactoryError::ModHost(status)
+            | FactoryError::Pipewire(status)
+            | FactoryError::Registry(status) => match status.code() {
+                Code::Unavailable | Code::DeadlineExceeded => Status::unavailable(message),
+                Code::NotFound => Status::not_found(message),
+                _ => Status::internal(message),
+            },
+            FactoryError::Validation(_) => Status::invalid_argument(message),
+            FactoryError::NotFound(_) => Status::not_found(message),
+            FactoryError::FailedPrecondition(_) => Status::failed_precondition(message),
+            FactoryError::Internal(_) => Status::internal(message),
+            FactoryError::Cancelled => Status::cancelled(message),
+        }
+    }
+}
+
+/// Waits for the factory to answer a request. Tonic drops this future when the
+/// client disconnects or the deadline from its grpc-timeout header passes,
+/// which cancels the request in the factory.
+async fn receive_response<T>(
+    receiver: tokio::sync::oneshot::Receiver<Result<T, FactoryError>>,
+) -> Result<T, Status> {
+    let response = receiver
+        .await
+        .map_err(|_| Status::internal("factory dropped the request"))?;
+    Ok(response?)
+}
+
+fn parameter_values(parameters: Vec<ParameterConfig>) -> Vec<PmxParameterValue> {
+    parameters
+        .into_iter()
+        .map(|parameter| PmxParameterValue {
+            symbol: parameter.symbol,
+            value: parameter.value,
+        })
+        .collect()
+}
+
+fn link_discrepancies(discrepancies: Vec<LinkDiscrepancy>) -> Vec<PmxLinkDiscrepancy> {
+    discrepancies
+        .into_iter()
+        .map(|discrepancy| PmxLinkDiscrepancy {
+            output_node_name: discrepancy.link.output_node_name,
+            output_port_id: discrepancy.link.output_port_id,
+            input_node_name: discrepancy.link.input_node_name,
+            input_port_id: discrepancy.link.input_port_id,
+            repaired: discrepancy.repaired,
+        })
+        .collect()
+}
+
+fn pmx_drifts(drifts: Vec<Drift>) -> Vec<PmxDrift> {
+    drifts
+        .into_iter()
+        .map(|drift| PmxDrift {
+            object: drift.object,
+            description: drift.description,
+            repaired: drift.repaired,
+        })
+        .collect()
 }
 
 #[tonic::async_trait]
@@ -53,25 +152,49 @@ impl PmxFactory for FactoryService {
             .log_info("Received create channel strip request");
         let (response_sender, response_receiver) = tokio::sync::oneshot::channel();
         let inner = request.into_inner();
+        let channel_type = PmxChannelStripType::try_from(inner.channel_type).map_err(|_| {
+            Status::invalid_argument(format!("unknown channel type {}", inner.channel_type))
+        })?;
+        let channel_layout = PmxChannelLayout::try_from(inner.channel_layout).map_err(|_| {
+            Status::invalid_argument(format!("unknown channel layout {}", inner.channel_layout))
+        })?;
         let factory_request = FactoryRequest::CreateChannelStrip {
             response_sender,
             name: inner.name,
-            channel_type: PmxChannelStripType::try_from(inner.channel_type).unwrap(),
+            channel_type,
+            channel_layout,
+            template: inner.template,
+            parameters: inner
+                .parameters
+                .into_iter()
+                .map(|preset| ParameterPreset {
+                    slot: preset.slot,
+                    symbol: preset.symbol,
+                    value: preset.value,
+                })
+                .collect(),
+            repair_links: inner.repair_links,
         };
-        self.sender.send(factory_request).unwrap();
-        let factory_response = response_receiver.await.unwrap();
+        self.send_request(factory_request)?;
+        let factory_response = receive_response(response_receiver).await?;
         Ok(Response::new(PmxChannelStrip {
             id: factory_response.id,
-            saturator_plugin_id: factory_response.saturator.id,
-            compressor_plugin_id: factory_response.compressor.id,
-            equalizer_plugin_id: factory_response.equalizer.id,
             name: factory_response.name,
             channel_type: factory_response.channel_type as i32,
-            cross_fader_plugin_id: match factory_response.cross_fader {
-                Some(fader) => Some(fader.id),
-                None => None,
-            },
-            gain_plugin_id: factory_response.gain.id,
+            cross_fader_plugin_id: factory_response.cross_fader.map(|fader| fader.id),
+            slots: factory_response
+                .slots
+                .into_iter()
+                .map(|slot| PmxChannelStripSlot {
+                    name: slot.name,
+                    plugin_uri: slot.plugin_uri,
+                    plugin_id: slot.plugin.id,
+                    parameters: parameter_values(slot.parameters),
+                })
+                .collect(),
+            template: factory_response.template,
+            channel_layout: factory_response.channel_layout as i32,
+            missing_links: link_discrepancies(factory_response.missing_links),
         }))
     }
 
@@ -85,15 +208,136 @@ impl PmxFactory for FactoryService {
         let factory_request = FactoryRequest::CreateOutputStage {
             name: inner.name,
             response_sender,
+            repair_links: inner.repair_links,
         };
-        self.sender.send(factory_request).unwrap();
-        let factory_response = response_receiver.await.unwrap();
+        self.send_request(factory_request)?;
+        let factory_response = receive_response(response_receiver).await?;
         Ok(Response::new(PmxOutputStage {
             id: factory_response.id,
             name: factory_response.name,
             left_channel_strip_id: factory_response.left_channel_strip_id,
             right_channel_strip_id: factory_response.right_channel_strip_id,
             cross_fader_plugin_id: factory_response.cross_fader_plugin_id,
+            missing_links: link_discrepancies(factory_response.missing_links),
+        }))
+    }
+    async fn delete_channel_strip(
+        &self,
+        request: Request<DeleteChannelStripRequest>,
+    ) -> Result<Response<DeleteChannelStripResponse>, Status> {
+        self.logger
+            .log_info("Received delete channel strip request");
+        let (response_sender, response_receiver) = tokio::sync::oneshot::channel();
+        let inner = request.into_inner();
+        let factory_request = FactoryRequest::DeleteChannelStrip {
+            id: inner.id,
+            response_sender,
+        };
+        self.send_request(factory_request)?;
+        receive_response(response_receiver).await?;
+        Ok(Response::new(DeleteChannelStripResponse {}))
+    }
+    async fn delete_output_stage(
+        &self,
+        request: Request<DeleteOutputStageRequest>,
+    ) -> Result<Response<DeleteOutputStageResponse>, Status> {
+        self.logger.log_info("Received delete output stage request");
+        let (response_sender, response_receiver) = tokio::sync::oneshot::channel();
+        let inner = request.into_inner();
+        let factory_request = FactoryRequest::DeleteOutputStage {
+            id: inner.id,
+            response_sender,
+        };
+        self.send_request(factory_request)?;
+        receive_response(response_receiver).await?;
+        Ok(Response::new(DeleteOutputStageResponse {}))
+    }
+    async fn list_templates(
+        &self,
+        _request: Request<ListTemplatesRequest>,
+    ) -> Result<Response<ListTemplatesResponse>, Status> {
+        self.logger.log_info("Received list templates request");
+        let (response_sender, response_receiver) = tokio::sync::oneshot::channel();
+        self.send_request(FactoryRequest::ListTemplates { response_sender })?;
+        let templates = receive_response(response_receiver).await?;
+        Ok(Response::new(ListTemplatesResponse {
+            templates: templates
+                .into_iter()
+                .map(|template| PmxChannelStripTemplate {
+                    name: template.name,
+                    slots: template
+                        .slots
+                        .into_iter()
+                        .map(|slot| PmxChannelStripTemplateSlot {
+                            name: slot.name,
+                            plugin_uri: slot.plugin_url,
+                            parameters: parameter_values(slot.parameters),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }))
+    }
+    async fn get_queue_status(
+        &self,
+        _request: Request<GetQueueStatusRequest>,
+    ) -> Result<Response<GetQueueStatusResponse>, Status> {
+        Ok(Response::new(GetQueueStatusResponse {
+            queued: self.queued_requests() as u32,
+            capacity: self.sender.max_capacity() as u32,
+        }))
+    }
+    async fn reconcile(
+        &self,
+        request: Request<ReconcileRequest>,
+    ) -> Result<Response<ReconcileResponse>, Status> {
+        self.logger.log_info("Received reconcile request");
+        let (response_sender, response_receiver) = tokio::sync::oneshot::channel();
+        let factory_request = FactoryRequest::Reconcile {
+            repair: request.into_inner().repair,
+            response_sender,
+        };
+        self.send_request(factory_request)?;
+        let drifts = receive_response(response_receiver).await?;
+        Ok(Response::new(ReconcileResponse {
+            drifts: pmx_drifts(drifts),
+        }))
+    }
+    async fn collect_garbage(
+        &self,
+        request: Request<CollectGarbageRequest>,
+    ) -> Result<Response<CollectGarbageResponse>, Status> {
+        self.logger.log_info("Received collect garbage request");
+        let (response_sender, response_receiver) = tokio::sync::oneshot::channel();
+        let factory_request = FactoryRequest::CollectGarbage {
+            confirm: request.into_inner().confirm,
+            response_sender,
+        };
+        self.send_request(factory_request)?;
+        let orphans = receive_response(response_receiver).await?;
+        Ok(Response::new(CollectGarbageResponse {
+            plugins: orphans
+                .into_iter()
+                .map(|orphan| PmxOrphanedPlugin {
+                    id: orphan.plugin.id,
+                    name: orphan.plugin.name,
+                    plugin_uri: orphan.plugin.plugin_uri,
+                    removed: orphan.removed,
+                })
+                .collect(),
+        }))
+    }
+    async fn restore_from_registry(
+        &self,
+        _request: Request<RestoreFromRegistryRequest>,
+    ) -> Result<Response<RestoreFromRegistryResponse>, Status> {
+        self.logger
+            .log_info("Received restore from registry request");
+        let (response_sender, response_receiver) = tokio::sync::oneshot::channel();
+        self.send_request(FactoryRequest::RestoreFromRegistry { response_sender })?;
+        let drifts = receive_response(response_receiver).await?;
+        Ok(Response::new(RestoreFromRegistryResponse {
+            drifts: pmx_drifts(drifts),
         }))
     }
 }
@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+use fr_logging::Logger;
+use tokio::sync::mpsc::Sender;
+use tokio::time::{interval_at, Instant, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+use crate::factory::FactoryRequest;
+
+/// Queues a reconciliation every `interval` until shutdown. A pass is skipped
+/// when the factory queue is full, and the next one runs at the following tick.
+pub async fn run_periodic_reconciliation(
+    sender: Sender<FactoryRequest>,
+    interval: Duration,
+    repair: bool,
+    shutdown: CancellationToken,
+    logger: Logger,
+) {
+    let mut ticks = interval_at(Instant::now() + interval, interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            // The sender goes away with this task, so the factory can drain
+            // its queue and stop.
+            _ = shutdown.cancelled() => return,
+        }
+
+        let (response_sender, response_receiver) = tokio::sync::oneshot::channel();
+        if sender
+            .try_send(FactoryRequest::Reconcile {
+                repair,
+                response_sender,
+            })
+            .is_err()
+        {
+            logger.log_error("Could not queue periodic reconciliation, skipping");
+            continue;
+        }
+        let drifts = tokio::select! {
+            response = response_receiver => response,
+            _ = shutdown.cancelled() => return,
+        };
+        match drifts {
+            Ok(Ok(drifts)) => {
+                for drift in drifts {
+                    let outcome = match drift.repaired {
+                        true => "repaired",
+                        false => "not repaired",
+                    };
+                    logger.log_error(&format!(
+                        "Drift in {}: {} ({outcome})",
+                        drift.object, drift.description
+                    ));
+                }
+            }
+            Ok(Err(error)) => logger.log_error(&format!("Periodic reconciliation failed: {error}")),
+            Err(_) => logger.log_error("Factory dropped the periodic reconciliation"),
+        }
+    }
+}
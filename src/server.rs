@@ -1,47 +1,115 @@
+use std::time::Duration;
+
+use dependencies::Dependency;
 use factory::{
     pmx::{
         mod_host::mod_host_proxy_client::ModHostProxyClient,
         pipewire::pipewire_client::PipewireClient, pmx_registry_client::PmxRegistryClient,
     },
-    Factory,
+    Factory, Journal,
+};
+use factory_service::{
+    pmx::factory::{pmx_factory_server::PmxFactoryServer, FILE_DESCRIPTOR_SET},
+    FactoryService,
 };
-use factory_service::{pmx::factory::pmx_factory_server::PmxFactoryServer, FactoryService};
-use tonic::transport::Server;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tonic::transport::{Endpoint, Server};
 
+mod dependencies;
 mod factory;
 mod factory_service;
+mod reconciler;
+
+const LOG_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     fr_logging::setup_logging();
     let (logging_sender, logging_receiver) = tokio::sync::mpsc::unbounded_channel();
     let logger_factory = fr_logging::LoggerFactory::new(logging_sender);
+    let logging_task = tokio::spawn(fr_logging::run_logging_task(logging_receiver));
+    let server_logger = logger_factory.new_logger(String::from("server"));
+
+    let shutdown = CancellationToken::new();
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            shutdown.cancel();
+        }
+    });
 
     let service_urls = fr_pmx_config_lib::read_service_urls();
-    let (factory_request_sender, factory_request_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let factory_config = fr_pmx_config_lib::read_factory_config();
+    let drain_timeout = Duration::from_millis(factory_config.shutdown.drain_timeout_ms);
+    let (factory_request_sender, factory_request_receiver) =
+        tokio::sync::mpsc::channel(factory_config.request_handling.queue_size.max(1));
     let factory_service_logger = logger_factory.new_logger(String::from("factory_service"));
+    if factory_config.reconcile.interval_ms > 0 {
+        tokio::spawn(reconciler::run_periodic_reconciliation(
+            factory_request_sender.clone(),
+            Duration::from_millis(factory_config.reconcile.interval_ms),
+            factory_config.reconcile.repair,
+            shutdown.clone(),
+            logger_factory.new_logger(String::from("reconciler")),
+        ));
+    }
     let service = PmxFactoryServer::new(FactoryService::new(
         factory_request_sender,
         factory_service_logger,
     ));
-    let server = Server::builder().add_service(service).serve(
-        service_urls
-            .pmx_factory_url
-            .replace("http://", "")
-            .parse()
-            .unwrap(),
-    );
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter
+        .set_service_status("", tonic_health::ServingStatus::NotServing)
+        .await;
+    health_reporter
+        .set_not_serving::<PmxFactoryServer<FactoryService>>()
+        .await;
+    let reflection_service = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .build_v1()?;
+    // On shutdown the server stops accepting connections and waits for the
+    // calls in flight, which keeps the factory request channel open until the
+    // factory answered them.
+    let server = Server::builder()
+        .add_service(health_service)
+        .add_service(reflection_service)
+        .add_service(service)
+        .serve_with_shutdown(
+            service_urls
+                .pmx_factory_url
+                .replace("http://", "")
+                .parse()
+                .unwrap(),
+            shutdown.clone().cancelled_owned(),
+        );
 
-    let factory_config = fr_pmx_config_lib::read_factory_config();
-    let mod_host_client = ModHostProxyClient::connect(service_urls.pmx_mod_host_proxy_url)
-        .await
-        .unwrap();
-    let registry_client = PmxRegistryClient::connect(service_urls.pmx_registry_url)
-        .await
-        .unwrap();
-    let pipewire_client = PipewireClient::connect(service_urls.pipewire_registry_url)
-        .await
-        .unwrap();
+    // The channels connect on first use, so the server is up right away even
+    // when the services the factory depends on are not.
+    let mod_host_endpoint = Endpoint::from_shared(service_urls.pmx_mod_host_proxy_url)?;
+    let registry_endpoint = Endpoint::from_shared(service_urls.pmx_registry_url)?;
+    let pipewire_endpoint = Endpoint::from_shared(service_urls.pipewire_registry_url)?;
+    let mod_host_client = ModHostProxyClient::new(mod_host_endpoint.connect_lazy());
+    let registry_client = PmxRegistryClient::new(registry_endpoint.connect_lazy());
+    let pipewire_client = PipewireClient::new(pipewire_endpoint.connect_lazy());
+    let health_task = tokio::spawn(dependencies::report_health(
+        vec![
+            Dependency::ModHost(mod_host_client.clone()),
+            Dependency::Registry(registry_client.clone()),
+            Dependency::Pipewire(pipewire_client.clone()),
+        ],
+        Duration::from_millis(factory_config.health.probe_interval_ms.max(1)),
+        Duration::from_millis(factory_config.health.probe_timeout_ms),
+        health_reporter,
+        logger_factory.new_logger(String::from("dependencies")),
+    ));
+
+    let journal = Journal::open(&factory_config.journal.path)?;
     let factory_logger = logger_factory.new_logger(String::from("factory"));
     let mut factory = Factory::new(
         factory_request_receiver,
@@ -49,12 +117,36 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         registry_client,
         pipewire_client,
         factory_config,
+        journal,
         factory_logger,
     );
 
-    tokio::select! {
-        _ = server => {Ok(())}
-        _ = factory.run() => {Ok(())}
-        _ = fr_logging::run_logging_task(logging_receiver) => {Ok(())}
-    }
+    let mut work = Box::pin(async { tokio::join!(server, factory.run()).0 });
+    let result = tokio::select! {
+        result = &mut work => result,
+        _ = shutdown.cancelled() => {
+            server_logger.log_info("Shutting down, draining requests in flight");
+            match tokio::time::timeout(drain_timeout, &mut work).await {
+                Ok(result) => result,
+                Err(_) => {
+                    server_logger.log_error(&format!(
+                        "Requests still in flight after {drain_timeout:?}, shutting down anyway"
+                    ));
+                    Ok(())
+                }
+            }
+        }
+    };
+    server_logger.log_info("Factory stopped");
+
+    // The logging task finishes once every logger is gone, after it wrote out
+    // everything that was logged.
+    health_task.abort();
+    drop(work);
+    drop(factory);
+    drop(server_logger);
+    drop(logger_factory);
+    let _ = tokio::time::timeout(LOG_FLUSH_TIMEOUT, logging_task).await;
+
+    Ok(result?)
 }